@@ -0,0 +1,54 @@
+use std::thread;
+
+use failure::Error;
+use log::error;
+use prometheus::{Encoder, TextEncoder};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Response};
+
+use crate::tls::{Listener, Tls};
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Admin {
+    address: String,
+    #[serde(default)]
+    tls: Option<Tls>,
+}
+
+impl Admin {
+    /// Serves prometheus metrics on `/metrics` from a background thread.
+    pub fn start(&self) -> Result<(), Error> {
+        let listener = Listener::bind(&self.address, self.tls.as_ref())?;
+
+        thread::spawn(move || loop {
+            let request = match listener.recv() {
+                Ok((request, _)) => request,
+                Err(e) => {
+                    error!("Admin server stopped: {}", e);
+                    break;
+                }
+            };
+
+            let response = match request.url() {
+                "/metrics" => {
+                    let encoder = TextEncoder::new();
+                    let mut buffer = Vec::new();
+                    match encoder.encode(&prometheus::gather(), &mut buffer) {
+                        Ok(_) => Response::from_data(buffer).with_header(
+                            Header::from_bytes("Content-Type", encoder.format_type()).unwrap(),
+                        ),
+                        Err(e) => Response::from_string(e.to_string()).with_status_code(500),
+                    }
+                }
+                "/ping" => Response::from_string("pong"),
+                _ => Response::from_data(Vec::new()).with_status_code(404),
+            };
+
+            if let Err(e) = request.respond(response) {
+                error!("Failed to send admin response: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use failure::{format_err, Error};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use prometheus::{IntCounterVec, Opts};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+lazy_static! {
+    static ref AUTH_FAILURES: IntCounterVec = {
+        let counter = IntCounterVec::new(
+            Opts::new(
+                "nekton_http_server_auth_failures_total",
+                "Requests rejected by http_server authentication.",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+        counter
+    };
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Auth {
+    /// HTTP basic auth against a file of plain text `user:password` lines.
+    Basic {
+        credentials_file: PathBuf,
+    },
+    Bearer {
+        tokens: Vec<String>,
+    },
+    Hmac {
+        secret: String,
+        #[serde(default)]
+        scheme: HmacScheme,
+        #[serde(default)]
+        header: Option<String>,
+        /// Maximum age in seconds of a `stripe` signature timestamp, 0 disables the check.
+        #[serde(default = "default_tolerance_secs")]
+        tolerance_secs: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HmacScheme {
+    /// `X-Hub-Signature-256: sha256=<hex>` over the body.
+    #[default]
+    Github,
+    /// `Stripe-Signature: t=<timestamp>,v1=<hex>` over `<timestamp>.<body>`.
+    Stripe,
+}
+
+fn default_tolerance_secs() -> u64 {
+    300
+}
+
+/// Largest body read for a signature check when the input sets no `max_body_size`.
+pub const MAX_SIGNED_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+}
+
+impl AuthError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AuthError::Missing => 401,
+            AuthError::Invalid => 403,
+        }
+    }
+}
+
+pub struct Authenticator<'a> {
+    auth: &'a Auth,
+    credentials: HashMap<String, String>,
+}
+
+impl Auth {
+    pub fn authenticator(&self) -> Result<Authenticator<'_>, Error> {
+        let credentials = match self {
+            Auth::Basic { credentials_file } => fs::read_to_string(credentials_file)?
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| {
+                    let mut parts = l.splitn(2, ':');
+                    match (parts.next(), parts.next()) {
+                        (Some(user), Some(password)) => Ok((user.to_owned(), password.to_owned())),
+                        _ => Err(format_err!("invalid line in {:?}", credentials_file)),
+                    }
+                })
+                .collect::<Result<_, Error>>()?,
+            _ => HashMap::new(),
+        };
+
+        Ok(Authenticator {
+            auth: self,
+            credentials,
+        })
+    }
+
+    /// Whether requests are signed, so their whole body has to be read before they are verified.
+    pub fn signs_body(&self) -> bool {
+        matches!(self, Auth::Hmac { .. })
+    }
+
+    /// The `WWW-Authenticate` challenge sent with a 401 response.
+    pub fn challenge(&self) -> Option<&'static str> {
+        match self {
+            Auth::Basic { .. } => Some("Basic realm=\"nekton\""),
+            Auth::Bearer { .. } => Some("Bearer"),
+            Auth::Hmac { .. } => None,
+        }
+    }
+}
+
+impl Authenticator<'_> {
+    /// Checks what the request's lower cased headers can tell, before its body is read.
+    pub fn verify_headers(&self, headers: &HashMap<String, String>) -> Result<(), AuthError> {
+        counted(self.check_headers(headers))
+    }
+
+    /// Checks the body against the signature in the headers, once they have been verified.
+    pub fn verify_body(
+        &self,
+        headers: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Result<(), AuthError> {
+        counted(self.check_body(headers, body))
+    }
+
+    fn check_headers(&self, headers: &HashMap<String, String>) -> Result<(), AuthError> {
+        match self.auth {
+            Auth::Basic { .. } => {
+                let encoded = authorization(headers, "Basic")?;
+                let decoded = base64::decode(encoded).map_err(|_| AuthError::Invalid)?;
+                let decoded = String::from_utf8(decoded).map_err(|_| AuthError::Invalid)?;
+                let mut parts = decoded.splitn(2, ':');
+                let (user, password) = (parts.next(), parts.next());
+                match (user.and_then(|u| self.credentials.get(u)), password) {
+                    (Some(expected), Some(password))
+                        if constant_time_eq(expected.as_bytes(), password.as_bytes()) =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(AuthError::Invalid),
+                }
+            }
+            Auth::Bearer { tokens } => {
+                let token = authorization(headers, "Bearer")?;
+                if tokens
+                    .iter()
+                    .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+                {
+                    Ok(())
+                } else {
+                    Err(AuthError::Invalid)
+                }
+            }
+            Auth::Hmac {
+                scheme: HmacScheme::Github,
+                ..
+            } => self.signature_header(headers).map(|_| ()),
+            Auth::Hmac {
+                scheme: HmacScheme::Stripe,
+                tolerance_secs,
+                ..
+            } => {
+                let (timestamp, _) = stripe_signature(self.signature_header(headers)?)?;
+                if *tolerance_secs > 0 {
+                    let timestamp = timestamp.parse::<u64>().map_err(|_| AuthError::Invalid)?;
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default();
+                    if now.abs_diff(timestamp) > *tolerance_secs {
+                        return Err(AuthError::Invalid);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn check_body(&self, headers: &HashMap<String, String>, body: &[u8]) -> Result<(), AuthError> {
+        let (secret, scheme) = match self.auth {
+            Auth::Hmac { secret, scheme, .. } => (secret, scheme),
+            _ => return Ok(()),
+        };
+        let value = self.signature_header(headers)?;
+        match scheme {
+            HmacScheme::Github => {
+                let signature = value.trim_start_matches("sha256=");
+                verify_signature(secret, &[body], signature)
+            }
+            HmacScheme::Stripe => {
+                let (timestamp, signatures) = stripe_signature(value)?;
+                let signed = [timestamp.as_bytes(), b".", body];
+                signatures
+                    .iter()
+                    .find_map(|v| verify_signature(secret, &signed, v).ok())
+                    .ok_or(AuthError::Invalid)
+            }
+        }
+    }
+
+    fn signature_header<'h>(
+        &self,
+        headers: &'h HashMap<String, String>,
+    ) -> Result<&'h str, AuthError> {
+        let (scheme, header) = match self.auth {
+            Auth::Hmac { scheme, header, .. } => (scheme, header),
+            _ => return Err(AuthError::Missing),
+        };
+        let header = header.as_ref().map(|h| h.to_ascii_lowercase());
+        let header = header.as_deref().unwrap_or(match scheme {
+            HmacScheme::Github => "x-hub-signature-256",
+            HmacScheme::Stripe => "stripe-signature",
+        });
+        headers.get(header).map(|v| &**v).ok_or(AuthError::Missing)
+    }
+}
+
+fn counted(result: Result<(), AuthError>) -> Result<(), AuthError> {
+    if let Err(e) = &result {
+        AUTH_FAILURES
+            .with_label_values(&[&e.status_code().to_string()])
+            .inc();
+    }
+    result
+}
+
+/// The timestamp and `v1` signatures of a `Stripe-Signature` header.
+fn stripe_signature(value: &str) -> Result<(&str, Vec<&str>), AuthError> {
+    let fields = value
+        .split(',')
+        .filter_map(|f| {
+            let mut kv = f.trim().splitn(2, '=');
+            Some((kv.next()?, kv.next()?))
+        })
+        .collect::<Vec<_>>();
+    let timestamp = fields
+        .iter()
+        .find(|(k, _)| k == &"t")
+        .map(|(_, v)| *v)
+        .ok_or(AuthError::Invalid)?;
+    let signatures = fields
+        .iter()
+        .filter(|(k, _)| k == &"v1")
+        .map(|(_, v)| *v)
+        .collect();
+    Ok((timestamp, signatures))
+}
+
+fn authorization<'a>(
+    headers: &'a HashMap<String, String>,
+    scheme: &str,
+) -> Result<&'a str, AuthError> {
+    let value = headers.get("authorization").ok_or(AuthError::Missing)?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(s), Some(credentials)) if s.eq_ignore_ascii_case(scheme) => Ok(credentials.trim()),
+        _ => Err(AuthError::Missing),
+    }
+}
+
+fn verify_signature(secret: &str, signed: &[&[u8]], signature: &str) -> Result<(), AuthError> {
+    let signature = hex::decode(signature).map_err(|_| AuthError::Invalid)?;
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).map_err(|_| AuthError::Invalid)?;
+    for part in signed {
+        mac.input(part);
+    }
+    mac.verify(&signature).map_err(|_| AuthError::Invalid)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    fn headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn verify(
+        authenticator: &Authenticator,
+        headers: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Result<(), AuthError> {
+        authenticator.verify_headers(headers)?;
+        authenticator.verify_body(headers, body)
+    }
+
+    fn sign(secret: &str, data: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(data);
+        hex::encode(mac.result().code())
+    }
+
+    #[test]
+    fn basic_auth_test() {
+        let credentials_file = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&credentials_file, "cheese:brie\ngoat:feta\n").unwrap();
+        let auth = Auth::Basic { credentials_file };
+        let authenticator = auth.authenticator().unwrap();
+
+        let valid = format!("Basic {}", base64::encode("goat:feta"));
+        let invalid = format!("Basic {}", base64::encode("goat:brie"));
+
+        assert_eq!(
+            verify(&authenticator, &headers(&[("authorization", &valid)]), b""),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("authorization", &invalid)]),
+                b""
+            ),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            verify(&authenticator, &headers(&[]), b""),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn bearer_auth_test() {
+        let auth = Auth::Bearer {
+            tokens: vec!["cheese".into()],
+        };
+        let authenticator = auth.authenticator().unwrap();
+
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("authorization", "Bearer cheese")]),
+                b""
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("authorization", "Bearer bacon")]),
+                b""
+            ),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("authorization", "Basic cheese")]),
+                b""
+            ),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn hmac_github_auth_test() {
+        let auth = Auth::Hmac {
+            secret: "cheese".into(),
+            scheme: HmacScheme::Github,
+            header: None,
+            tolerance_secs: default_tolerance_secs(),
+        };
+        let authenticator = auth.authenticator().unwrap();
+
+        let signature = format!("sha256={}", sign("cheese", b"brie"));
+
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("x-hub-signature-256", &signature)]),
+                b"brie"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("x-hub-signature-256", &signature)]),
+                b"feta"
+            ),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            verify(&authenticator, &headers(&[]), b"brie"),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn hmac_stripe_auth_test() {
+        let auth = Auth::Hmac {
+            secret: "cheese".into(),
+            scheme: HmacScheme::Stripe,
+            header: None,
+            tolerance_secs: default_tolerance_secs(),
+        };
+        let authenticator = auth.authenticator().unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signature = |t: u64| {
+            format!(
+                "t={},v1=00,v1={}",
+                t,
+                sign("cheese", format!("{}.brie", t).as_bytes())
+            )
+        };
+
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("stripe-signature", &signature(now))]),
+                b"brie"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("stripe-signature", &signature(now - 3600))]),
+                b"brie"
+            ),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            verify(
+                &authenticator,
+                &headers(&[("stripe-signature", &signature(now + 3600))]),
+                b"brie"
+            ),
+            Err(AuthError::Invalid)
+        );
+    }
+
+    #[test]
+    fn hmac_headers_checked_before_body_test() {
+        let auth = Auth::Hmac {
+            secret: "cheese".into(),
+            scheme: HmacScheme::Stripe,
+            header: None,
+            tolerance_secs: default_tolerance_secs(),
+        };
+        let authenticator = auth.authenticator().unwrap();
+
+        assert_eq!(
+            authenticator.verify_headers(&headers(&[("stripe-signature", "t=1000,v1=00")])),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            authenticator.verify_headers(&headers(&[])),
+            Err(AuthError::Missing)
+        );
+    }
+}
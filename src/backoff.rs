@@ -0,0 +1,131 @@
+use std::{thread, time::Duration};
+
+use failure::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Exponential backoff for components that connect out, flattened into their config.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Backoff {
+    #[serde(default = "default_min_backoff_ms")]
+    pub(crate) min_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub(crate) max_backoff_ms: u64,
+}
+
+fn default_min_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+/// How many times an output retries a message before failing the batch.
+pub(crate) fn default_max_retries() -> u32 {
+    5
+}
+
+impl Backoff {
+    pub(crate) fn delay(&self) -> Delay {
+        Delay {
+            next_ms: self.min_backoff_ms,
+            backoff: self.clone(),
+        }
+    }
+
+    /// Calls `attempt` until it succeeds, backing off in between, and fails with the last error
+    /// once it has been retried `max_retries` times.
+    pub(crate) fn retry<T>(
+        &self,
+        max_retries: u32,
+        target: &str,
+        mut attempt: impl FnMut() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut delay = self.delay();
+        for _ in 0..max_retries {
+            match attempt() {
+                Ok(t) => return Ok(t),
+                Err(e) => {
+                    warn!(
+                        "Failed to write to {}, retrying in {}ms: {}",
+                        target, delay.next_ms, e
+                    );
+                    delay.wait();
+                }
+            }
+        }
+        attempt().map_err(|e| {
+            format_err!(
+                "failed to write to {} after {} retries: {}",
+                target,
+                max_retries,
+                e
+            )
+        })
+    }
+}
+
+/// The wait before the next attempt, doubling each time up to the maximum.
+pub(crate) struct Delay {
+    backoff: Backoff,
+    next_ms: u64,
+}
+
+impl Delay {
+    pub(crate) fn next_ms(&self) -> u64 {
+        self.next_ms
+    }
+
+    pub(crate) fn wait(&mut self) {
+        thread::sleep(Duration::from_millis(self.next_ms));
+        self.next_ms = (self.next_ms * 2).min(self.backoff.max_backoff_ms);
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.next_ms = self.backoff.min_backoff_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKOFF: Backoff = Backoff {
+        min_backoff_ms: 1,
+        max_backoff_ms: 4,
+    };
+
+    #[test]
+    fn delay_test() {
+        let mut delay = BACKOFF.delay();
+        let mut waits = Vec::new();
+        for _ in 0..4 {
+            waits.push(delay.next_ms());
+            delay.wait();
+        }
+        assert_eq!(waits, vec![1, 2, 4, 4]);
+
+        delay.reset();
+        assert_eq!(delay.next_ms(), 1);
+    }
+
+    #[test]
+    fn retry_test() {
+        let mut attempts = 0;
+        let result = BACKOFF.retry(2, "cheese", || {
+            attempts += 1;
+            match attempts {
+                3 => Ok(attempts),
+                _ => Err(format_err!("mouldy")),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result = BACKOFF.retry(2, "cheese", || Err::<(), _>(format_err!("mouldy")));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "failed to write to cheese after 2 retries: mouldy"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    SecondsFormat, Utc,
+};
+use failure::{format_err, Error};
+use lazy_static::lazy_static;
+#[cfg(feature = "random")]
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+#[cfg(feature = "random")]
+use uuid::Uuid;
+
+use crate::{json_path::lookup, Message};
+
+lazy_static! {
+    static ref COUNTERS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+#[cfg(feature = "hostname")]
+lazy_static! {
+    static ref HOSTNAME: String = hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default();
+}
+
+/// A config string that may contain `${! ... }` functions evaluated against each message. The
+/// functions are listed in the README.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Metadata(String),
+    Json(Vec<String>),
+    Content,
+    Timestamp(Option<String>),
+    TimestampUnix,
+    Count(String),
+    #[cfg(feature = "random")]
+    UuidV4,
+    #[cfg(feature = "random")]
+    RandomInt,
+    #[cfg(feature = "hostname")]
+    Hostname,
+    BatchSize,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, Error> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("${!") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format_err!("unterminated interpolation in {:?}", source))?;
+            parts.push(Part::parse(rest[start + 3..start + end].trim())?);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+
+        Ok(Template {
+            source: source.to_owned(),
+            parts,
+        })
+    }
+
+    pub fn render(&self, message: &Message) -> String {
+        self.render_in_batch(message, 1)
+    }
+
+    pub fn render_in_batch(&self, message: &Message, batch_size: usize) -> String {
+        let mut json = None;
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Metadata(key) => {
+                    if let Some(value) = message.metadata.get(key) {
+                        rendered.push_str(value);
+                    }
+                }
+                Part::Json(path) => {
+                    let json = json.get_or_insert_with(|| {
+                        serde_json::from_slice(&message.data).unwrap_or(Value::Null)
+                    });
+                    match path.iter().try_fold(&*json, |v, key| lookup(v, key)) {
+                        Some(Value::String(s)) => rendered.push_str(s),
+                        Some(Value::Null) | None => (),
+                        Some(value) => rendered.push_str(&value.to_string()),
+                    }
+                }
+                Part::Content => rendered.push_str(&String::from_utf8_lossy(&message.data)),
+                Part::Timestamp(None) => {
+                    rendered.push_str(&Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true))
+                }
+                Part::Timestamp(Some(format)) => {
+                    rendered.push_str(&Utc::now().format(format).to_string())
+                }
+                Part::TimestampUnix => rendered.push_str(&Utc::now().timestamp().to_string()),
+                Part::Count(name) => {
+                    let mut counters = COUNTERS.lock().unwrap();
+                    let count = counters.entry(name.clone()).or_insert(0);
+                    *count += 1;
+                    rendered.push_str(&count.to_string());
+                }
+                #[cfg(feature = "random")]
+                Part::UuidV4 => rendered.push_str(&Uuid::new_v4().to_string()),
+                #[cfg(feature = "random")]
+                Part::RandomInt => rendered.push_str(&rand::thread_rng().gen::<u32>().to_string()),
+                #[cfg(feature = "hostname")]
+                Part::Hostname => rendered.push_str(&HOSTNAME),
+                Part::BatchSize => rendered.push_str(&batch_size.to_string()),
+            }
+        }
+        rendered
+    }
+}
+
+impl Part {
+    fn parse(function: &str) -> Result<Part, Error> {
+        let (name, argument) = match function.find(':') {
+            Some(i) => (&function[..i], Some(&function[i + 1..])),
+            None => (function, None),
+        };
+        match (name, argument) {
+            ("metadata", Some(key)) => Ok(Part::Metadata(key.to_owned())),
+            ("json", Some("")) => Ok(Part::Json(Vec::new())),
+            ("json", Some(path)) => Ok(Part::Json(path.split('.').map(str::to_owned).collect())),
+            ("content", None) => Ok(Part::Content),
+            ("timestamp", None) => Ok(Part::Timestamp(None)),
+            ("timestamp", Some(format)) => {
+                if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                    return Err(format_err!("invalid timestamp format {:?}", format));
+                }
+                Ok(Part::Timestamp(Some(format.to_owned())))
+            }
+            ("timestamp_unix", None) => Ok(Part::TimestampUnix),
+            ("count", Some(name)) => Ok(Part::Count(name.to_owned())),
+            #[cfg(feature = "random")]
+            ("uuid_v4", None) => Ok(Part::UuidV4),
+            #[cfg(feature = "random")]
+            ("random_int", None) => Ok(Part::RandomInt),
+            #[cfg(not(feature = "random"))]
+            ("uuid_v4", None) | ("random_int", None) => {
+                Err(format_err!("{} needs the random feature", name))
+            }
+            #[cfg(feature = "hostname")]
+            ("hostname", None) => Ok(Part::Hostname),
+            #[cfg(not(feature = "hostname"))]
+            ("hostname", None) => Err(format_err!("hostname needs the hostname feature")),
+            ("batch_size", None) => Ok(Part::BatchSize),
+            _ => Err(format_err!("unknown interpolation function {:?}", function)),
+        }
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Template {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Template::parse(&source).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_test() {
+        let mut message = Message {
+            data: b"brie".to_vec(),
+            ..Default::default()
+        };
+        message.metadata.insert("kind".into(), "cheese".into());
+
+        let template =
+            Template::parse("${! metadata:kind }-${!content}-${!metadata:missing}!").unwrap();
+        assert_eq!(template.render(&message), "cheese-brie-!");
+        assert_eq!(
+            template.to_string(),
+            "${! metadata:kind }-${!content}-${!metadata:missing}!"
+        );
+
+        assert_eq!(
+            Template::parse("cheese").unwrap().render(&message),
+            "cheese"
+        );
+        assert!(Template::parse("${! bacon }").is_err());
+        assert!(Template::parse("${! content").is_err());
+        assert!(serde_yaml::from_str::<Template>("${! bacon }").is_err());
+    }
+
+    #[test]
+    fn template_generated_values_test() {
+        let message = Message::default();
+
+        let count = Template::parse("${! count:template_test }").unwrap();
+        assert_eq!(count.render(&message), "1");
+        assert_eq!(count.render(&message), "2");
+        assert_eq!(
+            Template::parse("${!count:template_test}")
+                .unwrap()
+                .render(&message),
+            "3"
+        );
+
+        let timestamp = Template::parse("${! timestamp }").unwrap().render(&message);
+        assert!(chrono::DateTime::parse_from_rfc3339(&timestamp).is_ok());
+
+        let unix = Template::parse("${! timestamp_unix }")
+            .unwrap()
+            .render(&message);
+        assert!((unix.parse::<i64>().unwrap() - Utc::now().timestamp()).abs() < 5);
+    }
+
+    #[cfg(feature = "random")]
+    #[test]
+    fn template_random_values_test() {
+        let message = Message::default();
+
+        let uuid = Template::parse("${! uuid_v4 }").unwrap();
+        assert_ne!(uuid.render(&message), uuid.render(&message));
+        assert!(Uuid::parse_str(&uuid.render(&message)).is_ok());
+
+        let random = Template::parse("${! random_int }")
+            .unwrap()
+            .render(&message);
+        assert!(random.parse::<u32>().is_ok());
+    }
+
+    #[test]
+    fn template_message_values_test() {
+        let message = Message {
+            data: br#"{"cheese":{"name":"brie","ages":[2,4]},"rind":null}"#.to_vec(),
+            ..Default::default()
+        };
+
+        let render = |source: &str| {
+            Template::parse(source)
+                .unwrap()
+                .render_in_batch(&message, 3)
+        };
+        assert_eq!(render("${! json:cheese.name }"), "brie");
+        assert_eq!(render("${! json:cheese.ages.1 }"), "4");
+        assert_eq!(render("${! json:cheese.ages }"), "[2,4]");
+        assert_eq!(
+            render("${! json:rind }${! json:cheese.ages.9 }${! json:bacon }"),
+            ""
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(&render("${! json: }")).unwrap(),
+            serde_json::from_slice::<Value>(&message.data).unwrap()
+        );
+        assert_eq!(render("${! batch_size }"), "3");
+        #[cfg(feature = "hostname")]
+        assert_eq!(render("${! hostname }"), *HOSTNAME);
+        assert_eq!(
+            render("${! timestamp:%Y }"),
+            Utc::now().format("%Y").to_string()
+        );
+        assert_eq!(render("${! timestamp:%H:%M }").len(), 5);
+
+        let not_json = Message {
+            data: b"cheese".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Template::parse("${! json:name }")
+                .unwrap()
+                .render(&not_json),
+            ""
+        );
+        assert!(Template::parse("${! timestamp:%Q }").is_err());
+        assert!(Template::parse("${! json }").is_err());
+    }
+}
@@ -0,0 +1,466 @@
+use std::mem;
+
+use failure::{format_err, Error};
+use futures::stream::Stream;
+use log::warn;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::{
+    interpolate::Template,
+    json_path::{self, kind, lookup, lookup_mut},
+    Message, MessageBatch, ProcessHandler, Processor,
+};
+
+/// Works on messages as JSON documents, with paths into them written as dotted keys where
+/// numbers index arrays, e.g. `user.addresses.0.city`. An empty path is the whole document.
+/// Messages that aren't valid JSON, or that an operator can't be applied to, are left
+/// untouched with a `json_error`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "operator", rename_all = "snake_case")]
+enum Json {
+    /// Replaces the document with the value at `path`, or `null` if there isn't one.
+    Get { path: String },
+    /// Sets the value at `path`, creating objects along the way, or arrays for numeric keys.
+    Set { path: String, value: Setting },
+    /// Removes the values at `paths`.
+    Delete { paths: Vec<String> },
+    /// Keeps only the values at `paths`.
+    Select { paths: Vec<String> },
+    /// Turns nested objects into a single object with their keys joined by `separator`.
+    Flatten {
+        #[serde(default = "default_separator")]
+        separator: String,
+    },
+    /// Turns the array at `path` into one message per element.
+    Explode {
+        #[serde(default)]
+        path: String,
+    },
+    /// Merges every document of a batch into one, with colliding values other than objects
+    /// concatenated into arrays.
+    Merge,
+}
+
+fn default_separator() -> String {
+    ".".into()
+}
+
+/// A literal value to set, or a string that can contain interpolation functions.
+#[derive(Clone, Debug, PartialEq)]
+enum Setting {
+    Literal(Value),
+    Template(Template),
+}
+
+#[typetag::serde(name = "json")]
+impl Processor for Json {
+    fn create(&self) -> ProcessHandler {
+        let processor = self.clone();
+
+        Box::new(move |batches| {
+            let processor = processor.clone();
+            let result = batches.map(move |b| match processor {
+                Json::Merge => merge_batch(b),
+                _ => processor.map_batch(b),
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+impl Json {
+    fn map_batch(&self, mut batch: MessageBatch) -> MessageBatch {
+        let batch_size = batch.messages.len();
+        batch.messages = batch
+            .messages
+            .into_iter()
+            .flat_map(|message| match self.apply(&message, batch_size) {
+                Ok(messages) => messages,
+                Err(e) => vec![flag(message, e)],
+            })
+            .collect();
+        batch
+    }
+
+    fn apply(&self, message: &Message, batch_size: usize) -> Result<Vec<Message>, Error> {
+        let mut document = parse(message)?;
+        let document = match self {
+            Json::Get { path } => get(&document, path).cloned().unwrap_or(Value::Null),
+            Json::Set { path, value } => {
+                let value = match value {
+                    Setting::Literal(value) => value.clone(),
+                    Setting::Template(template) => {
+                        Value::String(template.render_in_batch(message, batch_size))
+                    }
+                };
+                set(&mut document, path, value)?;
+                document
+            }
+            Json::Delete { paths } => {
+                for path in paths {
+                    delete(&mut document, path);
+                }
+                document
+            }
+            Json::Select { paths } => {
+                let mut selected = Value::Null;
+                for path in paths {
+                    if let Some(value) = get(&document, path) {
+                        set(&mut selected, path, value.clone())?;
+                    }
+                }
+                if selected.is_null() {
+                    selected = Value::Object(Map::new());
+                }
+                selected
+            }
+            Json::Flatten { separator } => match document {
+                Value::Object(object) => {
+                    let mut flattened = Map::new();
+                    flatten(&mut flattened, "", separator, object);
+                    Value::Object(flattened)
+                }
+                other => return Err(format_err!("can't flatten {}", kind(&other))),
+            },
+            Json::Explode { path } => {
+                let elements = match get_mut(&mut document, path) {
+                    Some(Value::Array(elements)) => mem::take(elements),
+                    Some(other) => return Err(format_err!("can't explode {}", kind(other))),
+                    None => return Err(format_err!("nothing to explode at {:?}", path)),
+                };
+                return elements
+                    .iter()
+                    .map(|element| document_message(message, element))
+                    .collect();
+            }
+            Json::Merge => return Err(format_err!("merge applies to whole batches")),
+        };
+        Ok(vec![document_message(message, &document)?])
+    }
+}
+
+fn merge_batch(mut batch: MessageBatch) -> MessageBatch {
+    let mut merged: Option<(Message, Value)> = None;
+    let mut invalid = Vec::new();
+    for message in batch.messages {
+        match parse(&message) {
+            Ok(document) => match &mut merged {
+                Some((_, into)) => merge(into, document),
+                None => merged = Some((message, document)),
+            },
+            Err(e) => invalid.push(flag(message, e)),
+        }
+    }
+
+    batch.messages = match merged {
+        Some((first, document)) => match document_message(&first, &document) {
+            Ok(message) => vec![message],
+            Err(e) => vec![flag(first, e)],
+        },
+        None => Vec::new(),
+    };
+    batch.messages.extend(invalid);
+    batch
+}
+
+fn parse(message: &Message) -> Result<Value, Error> {
+    serde_json::from_slice(&message.data)
+        .map_err(|e| format_err!("message is not valid JSON: {}", e))
+}
+
+/// A message with the document as its content, keeping the original's metadata.
+fn document_message(message: &Message, document: &Value) -> Result<Message, Error> {
+    Ok(Message {
+        data: serde_json::to_vec(document)?,
+        metadata: message.metadata.clone(),
+    })
+}
+
+fn flag(mut message: Message, e: Error) -> Message {
+    warn!("Failed to process JSON message: {}", e);
+    message.metadata.insert("json_error".into(), e.to_string());
+    message
+}
+
+fn keys(path: &str) -> impl Iterator<Item = &str> {
+    path.split('.').filter(|key| !key.is_empty())
+}
+
+fn get<'a>(document: &'a Value, path: &str) -> Option<&'a Value> {
+    keys(path).try_fold(document, lookup)
+}
+
+fn get_mut<'a>(document: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    keys(path).try_fold(document, lookup_mut)
+}
+
+fn set(document: &mut Value, path: &str, value: Value) -> Result<(), Error> {
+    json_path::set(document, keys(path), value)
+}
+
+fn delete(document: &mut Value, path: &str) {
+    json_path::remove(document, &keys(path).collect::<Vec<_>>());
+}
+
+fn flatten(
+    flattened: &mut Map<String, Value>,
+    prefix: &str,
+    separator: &str,
+    object: Map<String, Value>,
+) {
+    for (key, value) in object {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}{}{}", prefix, separator, key)
+        };
+        match value {
+            Value::Object(ref nested) if !nested.is_empty() => {
+                if let Value::Object(nested) = value {
+                    flatten(flattened, &key, separator, nested);
+                }
+            }
+            value => {
+                flattened.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Objects are merged key by key, any other values that collide are concatenated as arrays,
+/// with a value that isn't an array taken as an array of one.
+fn merge(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Object(into), Value::Object(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (into, from) => {
+            let mut merged = match into.take() {
+                Value::Array(array) => array,
+                existing => vec![existing],
+            };
+            match from {
+                Value::Array(array) => merged.extend(array),
+                from => merged.push(from),
+            }
+            *into = Value::Array(merged);
+        }
+    }
+}
+
+impl Serialize for Setting {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Setting::Literal(value) => value.serialize(serializer),
+            Setting::Template(template) => template.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Setting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(source) => Template::parse(&source)
+                .map(Setting::Template)
+                .map_err(de::Error::custom),
+            value => Ok(Setting::Literal(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{no_metdata_batches, no_metdata_messages};
+
+    fn json(operator: &str, input: Vec<Message>) -> Vec<Message> {
+        let processor: Json = serde_yaml::from_str(operator).unwrap();
+        let batches = crate::run_processor!(processor, no_metdata_batches![input]);
+        batches.into_iter().flat_map(|b| b.messages).collect()
+    }
+
+    fn documents(messages: &[Message]) -> Vec<Value> {
+        messages
+            .iter()
+            .map(|m| serde_json::from_slice(&m.data).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn process_json_get_test() {
+        let input = no_metdata_messages![br#"{"cheeses":[{"name":"brie"}]}"#, b"{}"];
+        let output = json("operator: get\npath: cheeses.0.name", input);
+
+        assert_eq!(documents(&output), vec![Value::from("brie"), Value::Null]);
+    }
+
+    #[test]
+    fn process_json_set_test() {
+        let mut input = no_metdata_messages![br#"{"cheese":{"name":"brie"}}"#, b"null"];
+        input[0].metadata.insert("origin".into(), "france".into());
+
+        let output = json(
+            "operator: set\npath: cheese.origin\nvalue: '${! metadata:origin }'",
+            input.clone(),
+        );
+        assert_eq!(
+            documents(&output),
+            vec![
+                serde_json::json!({"cheese": {"name": "brie", "origin": "france"}}),
+                serde_json::json!({"cheese": {"origin": ""}}),
+            ]
+        );
+        assert_eq!(output[0].metadata["origin"], "france");
+
+        let output = json(
+            "operator: set\npath: cheese\nvalue: {soft: true}",
+            input.clone(),
+        );
+        assert_eq!(
+            documents(&output)[0],
+            serde_json::json!({"cheese": {"soft": true}})
+        );
+
+        let output = json("operator: set\npath: cheese.tags.0\nvalue: soft", input);
+        assert_eq!(
+            documents(&output),
+            vec![
+                serde_json::json!({"cheese": {"name": "brie", "tags": ["soft"]}}),
+                serde_json::json!({"cheese": {"tags": ["soft"]}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn process_json_delete_select_test() {
+        let input = || {
+            no_metdata_messages![
+                br#"{"name":"brie","milk":{"kind":"cow","raw":true},"tags":[1,2]}"#
+            ]
+        };
+
+        let output = json(
+            "operator: delete\npaths: [milk.raw, tags.0, missing.path]",
+            input(),
+        );
+        assert_eq!(
+            documents(&output)[0],
+            serde_json::json!({"name": "brie", "milk": {"kind": "cow"}, "tags": [2]})
+        );
+
+        let output = json(
+            "operator: select\npaths: [name, milk.kind, tags.1, missing]",
+            input(),
+        );
+        assert_eq!(
+            documents(&output)[0],
+            serde_json::json!({"name": "brie", "milk": {"kind": "cow"}, "tags": [null, 2]})
+        );
+    }
+
+    #[test]
+    fn process_json_flatten_test() {
+        let input = no_metdata_messages![
+            br#"{"name":"brie","milk":{"kind":"cow","farm":{"id":1}},"tags":[1],"empty":{}}"#,
+            b"[1]"
+        ];
+        let output = json("operator: flatten\nseparator: _", input);
+
+        assert_eq!(
+            serde_json::from_slice::<Value>(&output[0].data).unwrap(),
+            serde_json::json!({
+                "name": "brie",
+                "milk_kind": "cow",
+                "milk_farm_id": 1,
+                "tags": [1],
+                "empty": {},
+            })
+        );
+        assert_eq!(output[1].data, b"[1]");
+        assert_eq!(output[1].metadata["json_error"], "can't flatten an array");
+    }
+
+    #[test]
+    fn process_json_explode_test() {
+        let mut input = no_metdata_messages![br#"{"cheeses":["brie",{"name":"feta"}]}"#, b"[]"];
+        input[0].metadata.insert("origin".into(), "europe".into());
+        let output = json("operator: explode\npath: cheeses", input);
+
+        assert_eq!(output.len(), 3);
+        assert_eq!(output[0].data, br#""brie""#);
+        assert_eq!(output[1].data, br#"{"name":"feta"}"#);
+        assert_eq!(output[1].metadata["origin"], "europe");
+        assert_eq!(
+            output[2].metadata["json_error"],
+            "nothing to explode at \"cheeses\""
+        );
+
+        let output = json("operator: explode", no_metdata_messages![b"[1,2,3]"]);
+        assert_eq!(documents(&output), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn process_json_merge_test() {
+        let output = json(
+            "operator: merge",
+            no_metdata_messages![
+                br#"{"name":"brie","milk":{"kind":"cow"}}"#,
+                b"cheese",
+                br#"{"name":"feta","milk":{"raw":true}}"#,
+                br#"{"name":"gouda"}"#
+            ],
+        );
+
+        assert_eq!(output.len(), 2);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&output[0].data).unwrap(),
+            serde_json::json!({
+                "name": ["brie", "feta", "gouda"],
+                "milk": {"kind": "cow", "raw": true},
+            })
+        );
+        assert_eq!(output[1].data, b"cheese");
+        assert!(output[1].metadata.contains_key("json_error"));
+
+        // Arrays and other values collide the same way whichever comes first.
+        let output = json(
+            "operator: merge",
+            no_metdata_messages![
+                br#"{"tags":"soft","ages":[1,2],"milk":{"kind":"cow"}}"#,
+                br#"{"tags":["blue","french"],"ages":3,"milk":"goat"}"#
+            ],
+        );
+        assert_eq!(
+            documents(&output),
+            vec![serde_json::json!({
+                "tags": ["soft", "blue", "french"],
+                "ages": [1, 2, 3],
+                "milk": [{"kind": "cow"}, "goat"],
+            })]
+        );
+    }
+
+    #[test]
+    fn process_json_invalid_test() {
+        let output = json("operator: get\npath: name", no_metdata_messages![b"brie"]);
+
+        assert_eq!(output[0].data, b"brie");
+        assert_eq!(
+            output[0].metadata["json_error"],
+            "message is not valid JSON: expected value at line 1 column 1"
+        );
+        assert!(
+            serde_yaml::from_str::<Json>("operator: set\npath: a\nvalue: '${! bacon }'").is_err()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use failure::{format_err, Error};
+use serde_json::{Map, Value};
+
+/// The value under `key` of an object, or at the index `key` of an array.
+pub(crate) fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(object) => object.get(key),
+        Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+pub(crate) fn lookup_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Object(object) => object.get_mut(key),
+        Value::Array(array) => array.get_mut(key.parse::<usize>().ok()?),
+        _ => None,
+    }
+}
+
+/// Sets the value at `path`, creating containers in place of nulls along the way: an array for
+/// a numeric key and an object otherwise. Arrays are padded with nulls up to the index set.
+pub(crate) fn set<K: AsRef<str>>(
+    root: &mut Value,
+    path: impl IntoIterator<Item = K>,
+    value: Value,
+) -> Result<(), Error> {
+    let mut target = root;
+    for key in path {
+        let key = key.as_ref();
+        let index = key.parse::<usize>().ok();
+        if target.is_null() {
+            *target = match index {
+                Some(_) => Value::Array(Vec::new()),
+                None => Value::Object(Map::new()),
+            };
+        }
+        target = match (target, index) {
+            (Value::Object(object), _) => object.entry(key).or_insert(Value::Null),
+            (Value::Array(array), Some(i)) => {
+                if i >= array.len() {
+                    array.resize(i + 1, Value::Null);
+                }
+                &mut array[i]
+            }
+            (other, _) => return Err(format_err!("can't set {} of {}", key, kind(other))),
+        };
+    }
+    *target = value;
+    Ok(())
+}
+
+/// Removes the value at `path` if there is one, shifting later array elements down.
+pub(crate) fn remove<K: AsRef<str>>(root: &mut Value, path: &[K]) {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let parent = parents
+        .iter()
+        .try_fold(root, |value, key| lookup_mut(value, key.as_ref()));
+    match parent {
+        Some(Value::Object(object)) => {
+            object.remove(last.as_ref());
+        }
+        Some(Value::Array(array)) => {
+            if let Some(i) = last
+                .as_ref()
+                .parse::<usize>()
+                .ok()
+                .filter(|&i| i < array.len())
+            {
+                array.remove(i);
+            }
+        }
+        _ => (),
+    }
+}
+
+pub(crate) fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn json_path_test() {
+        let mut document = json!({"cheese": [{"name": "brie"}, {"name": "feta"}], "rind": null});
+
+        set(&mut document, ["cheese", "1", "name"], json!("goat")).unwrap();
+        set(&mut document, ["rind", "soft"], json!(true)).unwrap();
+        assert_eq!(
+            document,
+            json!({"cheese": [{"name": "brie"}, {"name": "goat"}], "rind": {"soft": true}})
+        );
+        assert_eq!(
+            set(&mut document, ["cheese", "name"], json!("edam"))
+                .unwrap_err()
+                .to_string(),
+            "can't set name of an array"
+        );
+        assert_eq!(
+            set(&mut document, ["rind", "soft", "very"], json!(1))
+                .unwrap_err()
+                .to_string(),
+            "can't set very of a boolean"
+        );
+
+        remove(&mut document, &["cheese", "0"]);
+        remove(&mut document, &["rind", "soft"]);
+        remove(&mut document, &["bacon", "crispy"]);
+        assert_eq!(document, json!({"cheese": [{"name": "goat"}], "rind": {}}));
+        assert_eq!(
+            ["cheese", "0", "name"]
+                .iter()
+                .try_fold(&document, |value, key| lookup(value, key)),
+            Some(&json!("goat"))
+        );
+    }
+
+    #[test]
+    fn json_path_array_test() {
+        // Numeric keys make arrays where there is nothing yet, and extend existing ones.
+        let mut document = json!({"name": "brie"});
+        set(&mut document, ["tags", "0"], json!("soft")).unwrap();
+        set(&mut document, ["tags", "2"], json!("french")).unwrap();
+        set(&mut document, ["milk", "0", "kind"], json!("cow")).unwrap();
+        assert_eq!(
+            document,
+            json!({
+                "name": "brie",
+                "tags": ["soft", null, "french"],
+                "milk": [{"kind": "cow"}]
+            })
+        );
+
+        // Objects keep numeric keys as keys.
+        let mut document = json!({"years": {}});
+        set(&mut document, ["years", "2020"], json!(true)).unwrap();
+        assert_eq!(document, json!({"years": {"2020": true}}));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fmt, fs, path::PathBuf, sync::Arc};
+
+use failure::{format_err, Error};
+use futures::stream::Stream;
+use jsonschema::JSONSchema;
+use log::warn;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use url::Url;
+
+use crate::{ProcessHandler, Processor};
+
+/// Validates messages against the JSON schema at `schema_path`, draft 7 unless the schema's
+/// `$schema` names 2019-09 or 2020-12. `$ref`s can point into the schema or at other schema
+/// files, but not at URLs. Valid messages pass untouched, and others get a
+/// `json_schema_error` listing each failing path and why, e.g. `/age: "ten" is not of type
+/// "integer"`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct JsonSchema {
+    schema_path: Schema,
+}
+
+/// A compiled schema, loaded when the config is.
+#[derive(Clone)]
+struct Schema {
+    path: PathBuf,
+    schema: Arc<JSONSchema>,
+}
+
+#[typetag::serde(name = "json_schema")]
+impl Processor for JsonSchema {
+    fn create(&self) -> ProcessHandler {
+        let schema = self.schema_path.clone();
+
+        Box::new(move |batches| {
+            let schema = schema.clone();
+            let result = batches.map(move |mut b| {
+                for message in &mut b.messages {
+                    if let Err(e) = schema.validate(&message.data) {
+                        warn!("Message failed JSON schema validation: {}", e);
+                        message
+                            .metadata
+                            .insert("json_schema_error".into(), e.to_string());
+                    }
+                }
+                b
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+impl Schema {
+    fn load(path: PathBuf) -> Result<Schema, Error> {
+        let mut schema: Value = serde_json::from_slice(&fs::read(&path)?)?;
+        // Relative `$ref`s resolve next to the schema file, unless it has an `$id` of its own.
+        if let Value::Object(object) = &mut schema {
+            if !object.contains_key("$id") {
+                let url = Url::from_file_path(fs::canonicalize(&path)?)
+                    .map_err(|_| format_err!("invalid JSON schema path {:?}", path))?;
+                object.insert("$id".into(), Value::String(url.into_string()));
+            }
+        }
+        let schema = JSONSchema::compile(&schema)
+            .map_err(|e| format_err!("invalid JSON schema {:?}: {}", path, e))?;
+        Ok(Schema {
+            path,
+            schema: Arc::new(schema),
+        })
+    }
+
+    fn validate(&self, data: &[u8]) -> Result<(), Error> {
+        let instance: Value = serde_json::from_slice(data)
+            .map_err(|e| format_err!("message is not valid JSON: {}", e))?;
+        self.schema.validate(&instance).map_err(|errors| {
+            let errors = errors
+                .map(|e| match e.instance_path.to_string() {
+                    path if path.is_empty() => format!("/: {}", e),
+                    path => format!("{}: {}", path, e),
+                })
+                .collect::<Vec<_>>();
+            format_err!("{}", errors.join("; "))
+        })
+    }
+}
+
+impl fmt::Debug for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Schema").field(&self.path).finish()
+    }
+}
+
+impl PartialEq for Schema {
+    fn eq(&self, other: &Schema) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Serialize for Schema {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.path.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schema {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = PathBuf::deserialize(deserializer)?;
+        Schema::load(path).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{no_metdata_batches, no_metdata_messages, Message};
+
+    #[test]
+    fn process_json_schema_test() {
+        // A draft 7 schema reaching `user` through `definitions` and `tags` through another file.
+        let processor: JsonSchema =
+            serde_yaml::from_str("schema_path: config_examples/event.schema.json").unwrap();
+
+        let messages = no_metdata_messages![
+            br#"{"id":"a1","user":{"name":"brie","age":3},"tags":["soft"]}"#,
+            br#"{"id":"a2","user":{"name":"feta","age":"ten"},"tags":["salty",1]}"#,
+            b"[]",
+            b"cheese"
+        ];
+        let batches = crate::run_processor!(processor, no_metdata_batches![messages.clone()]);
+        let output = &batches[0].messages;
+
+        assert_eq!(output[0], messages[0]);
+        assert_eq!(output[1].data, messages[1].data);
+        assert_eq!(
+            output[1].metadata["json_schema_error"],
+            r#"/tags/1: 1 is not of type "string"; /user/age: "ten" is not of type "integer""#
+        );
+        assert_eq!(
+            output[2].metadata["json_schema_error"],
+            r#"/: [] is not of type "object""#
+        );
+        assert_eq!(
+            output[3].metadata["json_schema_error"],
+            "message is not valid JSON: expected value at line 1 column 1"
+        );
+    }
+
+    #[test]
+    fn json_schema_config_error_test() {
+        assert!(serde_yaml::from_str::<JsonSchema>("schema_path: missing.json").is_err());
+        assert!(
+            serde_yaml::from_str::<JsonSchema>("schema_path: config_examples/std.yml").is_err()
+        );
+    }
+}
@@ -1,152 +1,1209 @@
-use std::{collections::HashMap, str};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use failure::Error;
-use futures::{future::ok, Future, Stream};
-use log::debug;
+use failure::{format_err, Error};
+use futures::{future::err, stream, sync::oneshot, Future, Stream};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
 use rdkafka::client::ClientContext;
 use rdkafka::config::ClientConfig;
-use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
-use rdkafka::error::KafkaResult;
-use rdkafka::message::Message as _;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::message::{BorrowedMessage, Headers, Message as _, OwnedHeaders};
+use rdkafka::producer::{
+    BaseRecord, DeliveryResult, FutureRecord, Producer, ProducerContext, ThreadedProducer,
+};
+use rdkafka::statistics::Statistics;
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
 use serde::{Deserialize, Serialize};
-use typetag::serde;
 
-use crate::{BoxFn, Message, MessageBatch, Sink, Source, Transaction, WriteHandler};
+use crate::{
+    interpolate::Template, BoxFuture, Message, MessageBatch, Sink, Source, Transaction,
+    TransactionHandler, WriteHandler,
+};
 
-struct CustomContext;
+/// Partition assignment changes reported by the consumer's rebalance callbacks.
+enum RebalanceEvent {
+    Assigned(Vec<(String, i32)>),
+}
 
-impl ClientContext for CustomContext {}
+lazy_static! {
+    static ref CONSUMER_LAG: IntGaugeVec = {
+        let gauge = IntGaugeVec::new(
+            Opts::new(
+                "nekton_kafka_consumer_lag",
+                "Messages between the high water mark and the committed offset of each assigned \
+                 partition, updated from librdkafka statistics.",
+            ),
+            &["topic", "partition"],
+        )
+        .unwrap();
+        prometheus::register(Box::new(gauge.clone())).unwrap();
+        gauge
+    };
+    static ref ASSIGNED_PARTITIONS: IntGauge = {
+        let gauge = IntGauge::with_opts(Opts::new(
+            "nekton_kafka_consumer_assigned_partitions",
+            "Partitions currently assigned to the kafka input.",
+        ))
+        .unwrap();
+        prometheus::register(Box::new(gauge.clone())).unwrap();
+        gauge
+    };
+    static ref REBALANCES: IntCounterVec = {
+        let counter = IntCounterVec::new(
+            Opts::new(
+                "nekton_kafka_consumer_rebalances_total",
+                "Rebalances of the kafka input's consumer group.",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+        counter
+    };
+    static ref COMMIT_ERRORS: IntCounter = {
+        let counter = IntCounter::with_opts(Opts::new(
+            "nekton_kafka_consumer_commit_errors_total",
+            "Offset commits of the kafka input that failed.",
+        ))
+        .unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+        counter
+    };
+    /// Consumers of `exactly_once` kafka inputs by group id, for the kafka output to commit
+    /// their offsets in its transactions.
+    static ref GROUPS: Mutex<HashMap<String, Weak<BaseConsumer<CustomContext>>>> =
+        Mutex::new(HashMap::new());
+}
+
+struct CustomContext {
+    rebalances: Mutex<Sender<RebalanceEvent>>,
+    /// Partitions assigned by the group, whose lag is dropped once they're revoked.
+    assigned: Mutex<Vec<(String, i32)>>,
+    revocation: Arc<Revocation>,
+}
+
+/// Settles in-flight work from the revoke callback, see `Records::on_revoke`.
+#[derive(Default)]
+struct Revocation {
+    /// The consumer to commit through, set once it has been created.
+    consumer: Mutex<Weak<BaseConsumer<CustomContext>>>,
+    revoke: Mutex<Option<Revoke>>,
+}
+
+impl CustomContext {
+    fn new(rebalances: Sender<RebalanceEvent>, revocation: Arc<Revocation>) -> CustomContext {
+        CustomContext {
+            rebalances: Mutex::new(rebalances),
+            assigned: Mutex::new(Vec::new()),
+            revocation,
+        }
+    }
+
+    fn assigned(&self, partitions: Vec<(String, i32)>) {
+        let mut assigned = self.assigned.lock().unwrap();
+        for (topic, partition) in assigned.iter() {
+            let _ = CONSUMER_LAG.remove_label_values(&[topic, &partition.to_string()]);
+        }
+        ASSIGNED_PARTITIONS.set(partitions.len() as i64);
+        *assigned = partitions;
+    }
+}
+
+/// Lag is only known once a partition has both a high water mark and a committed offset.
+fn record_lag(topic: &str, partition: i32, high: i64, committed: i64) {
+    if high >= 0 && committed >= 0 {
+        CONSUMER_LAG
+            .with_label_values(&[topic, &partition.to_string()])
+            .set((high - committed).max(0));
+    }
+}
+
+impl ClientContext for CustomContext {
+    /// Called every `statistics.interval.ms`.
+    fn stats(&self, statistics: Statistics) {
+        debug!("Consumer statistics: {:?}", statistics);
+        for (name, topic) in &statistics.topics {
+            for p in topic.partitions.values().filter(|p| p.desired) {
+                record_lag(name, p.partition, p.hi_offset, p.committed_offset);
+            }
+        }
+    }
+}
 
 impl ConsumerContext for CustomContext {
     fn pre_rebalance(&self, rebalance: &Rebalance) {
         debug!("Pre rebalance {:?}", rebalance);
+        match rebalance {
+            Rebalance::Revoke => {
+                REBALANCES.with_label_values(&["revoke"]).inc();
+                self.assigned(Vec::new());
+                // Nothing is committed while the consumer is being dropped.
+                let consumer = self.revocation.consumer.lock().unwrap().upgrade();
+                if let (Some(consumer), Some(revoke)) =
+                    (consumer, &mut *self.revocation.revoke.lock().unwrap())
+                {
+                    revoke(&*consumer);
+                }
+            }
+            Rebalance::Error(e) => {
+                REBALANCES.with_label_values(&["error"]).inc();
+                warn!("Rebalance failed: {}", e);
+            }
+            Rebalance::Assign(_) => (),
+        }
     }
 
     fn post_rebalance(&self, rebalance: &Rebalance) {
         debug!("Post rebalance {:?}", rebalance);
+        if let Rebalance::Assign(partitions) = rebalance {
+            REBALANCES.with_label_values(&["assign"]).inc();
+            let partitions: Vec<_> = partitions
+                .elements()
+                .iter()
+                .map(|p| (p.topic().to_owned(), p.partition()))
+                .collect();
+            self.assigned(partitions.clone());
+            let _ = self
+                .rebalances
+                .lock()
+                .unwrap()
+                .send(RebalanceEvent::Assigned(partitions));
+        }
     }
 
-    fn commit_callback(
-        &self,
-        result: KafkaResult<()>,
-        _offsets: *mut rdkafka_sys::RDKafkaTopicPartitionList,
-    ) {
+    fn commit_callback(&self, result: KafkaResult<()>, _offsets: &TopicPartitionList) {
         debug!("Committing offsets: {:?}", result);
+        if let Err(e) = result {
+            COMMIT_ERRORS.inc();
+            warn!("Offset commit failed: {}", e);
+        }
     }
 }
 
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 struct KafkaIn {
+    /// Topics to subscribe to, those starting with `^` are regular expressions.
+    #[serde(default)]
     topics: Vec<String>,
+    /// Partitions to assign directly instead of subscribing to `topics` through a consumer group.
+    #[serde(default)]
+    partitions: Vec<TopicPartition>,
+    /// Where to start reading `partitions` from, the committed offset when unset.
+    #[serde(default)]
+    start: Option<StartPosition>,
+    /// Stop once every partition in `partitions` reaches this position.
+    #[serde(default)]
+    stop: Option<StopPosition>,
     config: HashMap<String, String>,
-    #[serde(skip)]
-    consume_count: u32,
+    /// Emit records with a null payload as empty messages flagged with `kafka_tombstone`.
+    #[serde(default)]
+    emit_tombstones: bool,
+    #[serde(default)]
+    commit: CommitStrategy,
+    /// Run a pipeline per assigned partition, so a slow partition doesn't hold up the others.
+    #[serde(default)]
+    partition_parallel: bool,
+    /// Messages queued for a partition's pipeline before the consumer waits for it.
+    #[serde(default = "default_partition_backlog")]
+    partition_backlog: usize,
+    /// Leave committing offsets to a kafka output with `exactly_once` set to this group, which
+    /// commits them in the same transaction as what it produced.
+    #[serde(default)]
+    exactly_once: bool,
+}
+
+fn default_partition_backlog() -> usize {
+    1000
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct TopicPartition {
+    topic: String,
+    partition: i32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StartPosition {
+    Beginning,
+    End,
+    Offset(i64),
+    /// The first offset at or after this timestamp in milliseconds.
+    Timestamp(i64),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum StopPosition {
+    /// The last offset of each partition when the input started.
+    HighWaterMark,
+    /// The first message at or after this timestamp in milliseconds, or the high water mark if
+    /// that comes first.
+    Timestamp(i64),
+}
+
+/// How often librdkafka reports the statistics consumer lag comes from, unless configured.
+const DEFAULT_STATISTICS_INTERVAL_MS: &str = "5000";
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl KafkaIn {
+    /// Assigns `partitions` at their start positions, returning where each of them stops.
+    fn assign<C: Consumer<CustomContext>>(&self, consumer: &C) -> Result<StopOffsets, Error> {
+        let times = match self.start {
+            Some(StartPosition::Timestamp(timestamp)) => {
+                let mut times = TopicPartitionList::new();
+                for p in &self.partitions {
+                    times.add_partition_offset(&p.topic, p.partition, Offset::Offset(timestamp))?;
+                }
+                Some(consumer.offsets_for_times(times, METADATA_TIMEOUT)?)
+            }
+            _ => None,
+        };
+
+        let mut assignment = TopicPartitionList::new();
+        let mut stops = StopOffsets {
+            remaining: HashMap::new(),
+            timestamp: match self.stop {
+                Some(StopPosition::Timestamp(timestamp)) => Some(timestamp),
+                _ => None,
+            },
+        };
+        for p in &self.partitions {
+            let (low, high) = consumer.fetch_watermarks(&p.topic, p.partition, METADATA_TIMEOUT)?;
+            let start = match (self.start, &times) {
+                (Some(StartPosition::Beginning), _) => Offset::Beginning,
+                (Some(StartPosition::End), _) => Offset::End,
+                (Some(StartPosition::Offset(offset)), _) => Offset::Offset(offset),
+                (Some(StartPosition::Timestamp(_)), Some(times)) => times
+                    .find_partition(&p.topic, p.partition)
+                    .map(|t| t.offset())
+                    .unwrap_or(Offset::End),
+                _ => Offset::Stored,
+            };
+            debug!(
+                "Assigning {} partition {} from {:?}",
+                p.topic, p.partition, start
+            );
+            assignment.add_partition_offset(&p.topic, p.partition, start)?;
+
+            let start = match start {
+                Offset::Offset(offset) => offset,
+                Offset::End => high,
+                _ => low,
+            };
+            if start < high {
+                stops.remaining.insert((p.topic.clone(), p.partition), high);
+            }
+        }
+
+        consumer.assign(&assignment)?;
+        ASSIGNED_PARTITIONS.set(self.partitions.len() as i64);
+        Ok(stops)
+    }
+}
+
+/// The partitions that still have messages to deliver before the input stops.
+#[derive(Debug, Default)]
+struct StopOffsets {
+    /// The high water mark of each unfinished partition.
+    remaining: HashMap<(String, i32), i64>,
+    timestamp: Option<i64>,
+}
+
+impl StopOffsets {
+    fn finished(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// Whether a message comes before the stop position of its partition.
+    fn admit(&mut self, topic: &str, partition: i32, offset: i64, timestamp: Option<i64>) -> bool {
+        let key = (topic.to_owned(), partition);
+        let high = match self.remaining.get(&key) {
+            Some(high) => *high,
+            None => return false,
+        };
+        if let (Some(stop), Some(timestamp)) = (self.timestamp, timestamp) {
+            if timestamp >= stop {
+                self.remaining.remove(&key);
+                return false;
+            }
+        }
+        if offset + 1 >= high {
+            self.remaining.remove(&key);
+        }
+        true
+    }
 }
 
 #[typetag::serde(name = "kafka")]
 impl Source for KafkaIn {
-    fn start(&self, f: BoxFn<Transaction, Error>) -> Result<(), Error> {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
         let mut config = &mut ClientConfig::new();
 
-        for (k, v) in &self.config {
+        for (k, v) in &self.consumer_config()? {
             config = config.set(k, v);
         }
 
-        let consumer: StreamConsumer<CustomContext> = config
-            .create_with_context(CustomContext)
-            .expect("Consumer creation failed");
-
-        let topics: Vec<&str> = self.topics.iter().map(|s| &**s).collect();
-
-        consumer
-            .subscribe(&topics)
-            .expect("Can't subscribe to specified topics");
-
-        let message_stream = consumer.start();
-
-        let mut consumed_messages = 0;
-        for message in message_stream.wait() {
-            match message {
-                Err(_) => panic!("Error while reading from stream."),
-                Ok(Err(e)) => panic!("Kafka error: {}", e),
-                Ok(Ok(m)) => {
-                    match m.payload_view::<[u8]>() {
-                        None => (),
-                        Some(Ok(payload)) => {
-                            let mut batch = MessageBatch::default();
-                            batch.messages.push(Message {
-                                data: payload.into(),
-                                ..Default::default()
-                            });
-
-                            f(Transaction { batch }).wait().unwrap();
-
-                            consumer.commit_message(&m, CommitMode::Sync).unwrap();
-                            if self.consume_count != 0 {
-                                consumed_messages += 1;
-                                if consumed_messages >= self.consume_count {
-                                    break;
-                                }
-                            }
+        let (rebalances_tx, rebalances) = channel();
+        let revocation = Arc::new(Revocation::default());
+        let consumer: Arc<BaseConsumer<CustomContext>> = Arc::new(
+            config
+                .create_with_context(CustomContext::new(rebalances_tx, revocation.clone()))
+                .map_err(|e| format_err!("failed to create consumer: {}", e))?,
+        );
+        *revocation.consumer.lock().unwrap() = Arc::downgrade(&consumer);
+        if self.exactly_once {
+            let group_id = self
+                .config
+                .get("group.id")
+                .ok_or_else(|| format_err!("exactly_once needs a group.id"))?;
+            GROUPS
+                .lock()
+                .unwrap()
+                .insert(group_id.clone(), Arc::downgrade(&consumer));
+        }
+
+        let mut stops = None;
+        if self.partitions.is_empty() {
+            if self.start.is_some() || self.stop.is_some() {
+                return Err(format_err!(
+                    "start and stop positions need partitions to assign"
+                ));
+            }
+
+            let topics: Vec<&str> = self.topics.iter().map(|s| &**s).collect();
+
+            consumer
+                .subscribe(&topics)
+                .map_err(|e| format_err!("failed to subscribe to {:?}: {}", topics, e))?;
+        } else {
+            let assigned = self.assign(&*consumer)?;
+            if self.stop.is_some() {
+                stops = Some(assigned);
+            }
+        }
+
+        let mut records = ConsumerRecords {
+            consumer,
+            rebalances,
+            revocation,
+        };
+
+        self.consume(&mut records, f, stops)
+    }
+}
+
+impl KafkaIn {
+    /// The user's config with librdkafka's own offset handling turned off, as offsets are only
+    /// stored and committed once the pipeline has acknowledged a message, and statistics on so
+    /// consumer lag is reported.
+    fn consumer_config(&self) -> Result<HashMap<String, String>, Error> {
+        let mut config = self.config.clone();
+        config
+            .entry("statistics.interval.ms".to_owned())
+            .or_insert_with(|| DEFAULT_STATISTICS_INTERVAL_MS.to_owned());
+        for key in &["enable.auto.commit", "enable.auto.offset.store"] {
+            match config.insert((*key).to_owned(), "false".to_owned()) {
+                Some(value) if value != "false" => {
+                    return Err(format_err!(
+                        "{} can't be {}, offsets are committed once messages are delivered",
+                        key,
+                        value
+                    ))
+                }
+                _ => (),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Feeds records to the pipeline, committing their offsets as they are acknowledged.
+    fn consume(
+        &self,
+        records: &mut impl Records,
+        f: TransactionHandler,
+        mut stops: Option<StopOffsets>,
+    ) -> Result<(), Error> {
+        // Offsets can only be committed for a consumer group, and in exactly_once mode the
+        // output commits them.
+        let commits = self.config.contains_key("group.id") && !self.exactly_once;
+
+        let f = Arc::new(f);
+        let in_flight = Arc::new(Mutex::new(InFlight {
+            workers: PartitionWorkers::new(f.clone(), self.partition_backlog),
+            offsets: OffsetTracker::default(),
+            uncommitted: 0,
+            commits,
+            failed: None,
+        }));
+        let revoked = in_flight.clone();
+        records.on_revoke(Box::new(move |consumer| {
+            revoked.lock().unwrap().revoke(consumer)
+        }));
+
+        let mut last_commit = Instant::now();
+        while let Some(record) = records.poll() {
+            // Only held between polls, the revoke callback runs inside them.
+            let mut in_flight = in_flight.lock().unwrap();
+            let InFlight {
+                workers,
+                offsets,
+                uncommitted,
+                failed,
+                ..
+            } = &mut *in_flight;
+            if let Some(e) = failed.take() {
+                return Err(e);
+            }
+            if stops.as_ref().is_some_and(StopOffsets::finished) {
+                break;
+            }
+
+            for rebalance in records.rebalances() {
+                match rebalance {
+                    RebalanceEvent::Assigned(partitions) if self.partition_parallel => {
+                        for (topic, partition) in partitions {
+                            workers.spawn(topic, partition);
                         }
-                        Some(Err(e)) => {
-                            panic!("Error while deserializing message payload: {:?}", e);
+                    }
+                    RebalanceEvent::Assigned(_) => (),
+                }
+            }
+            *uncommitted += workers.acked(offsets)?;
+
+            if let Some(r) = record? {
+                if let Some(stops) = &mut stops {
+                    if !stops.admit(&r.topic, r.partition, r.offset, r.timestamp) {
+                        continue;
+                    }
+                }
+
+                let data = match r.payload {
+                    Some(payload) => payload,
+                    None if self.emit_tombstones => Vec::new(),
+                    None => {
+                        offsets.acked(&r.topic, r.partition, r.offset);
+                        continue;
+                    }
+                };
+
+                offsets.received(&r.topic, r.partition, r.offset);
+
+                let mut batch = MessageBatch::default();
+                batch.messages.push(Message {
+                    data,
+                    metadata: r.metadata,
+                });
+
+                if self.partition_parallel {
+                    let partition = workers.spawn(r.topic, r.partition);
+                    partition.send(Transaction { batch }, r.offset)?;
+                } else {
+                    f(Transaction { batch }).wait()?;
+                    offsets.acked(&r.topic, r.partition, r.offset);
+                    *uncommitted += 1;
+                }
+            }
+
+            if commits && self.commit.due(*uncommitted, last_commit.elapsed()) {
+                commit(records, offsets, self.commit.mode.into());
+                *uncommitted = 0;
+                last_commit = Instant::now();
+            }
+        }
+
+        let mut in_flight = in_flight.lock().unwrap();
+        let InFlight {
+            workers, offsets, ..
+        } = &mut *in_flight;
+        workers.stop(offsets)?;
+        if commits {
+            commit(records, offsets, CommitMode::Sync);
+        }
+        Ok(())
+    }
+}
+
+/// A record copied out of the consumer, along with the metadata the pipeline sees.
+#[derive(Debug)]
+struct Record {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    timestamp: Option<i64>,
+    payload: Option<Vec<u8>>,
+    metadata: HashMap<String, String>,
+}
+
+impl Record {
+    fn new(m: &BorrowedMessage) -> Record {
+        Record {
+            topic: m.topic().to_owned(),
+            partition: m.partition(),
+            offset: m.offset(),
+            timestamp: m.timestamp().to_millis(),
+            payload: m.payload().map(|p| p.to_vec()),
+            metadata: message_metadata(m),
+        }
+    }
+}
+
+/// The consumer as seen by `KafkaIn::consume`, so it can be driven by a stand-in in tests.
+trait Records: Committer {
+    /// The next record, `Ok(None)` when nothing arrived within the poll interval and `None`
+    /// once the consumer has stopped.
+    fn poll(&mut self) -> Option<Result<Option<Record>, Error>>;
+
+    fn rebalances(&mut self) -> Vec<RebalanceEvent>;
+
+    /// Has `revoke` run from the revoke callback, before the partitions are given up.
+    fn on_revoke(&mut self, revoke: Revoke);
+}
+
+/// Called with the consumer when partitions are revoked, to commit through.
+type Revoke = Box<dyn FnMut(&dyn Committer) + Send>;
+
+trait Committer {
+    fn commit(&self, offsets: &[(String, i32, i64)], mode: CommitMode) -> Result<(), Error>;
+}
+
+impl Committer for BaseConsumer<CustomContext> {
+    fn commit(&self, offsets: &[(String, i32, i64)], mode: CommitMode) -> Result<(), Error> {
+        let mut list = TopicPartitionList::new();
+        for (topic, partition, offset) in offsets {
+            list.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+        }
+        Ok(Consumer::commit(self, &list, mode)?)
+    }
+}
+
+struct ConsumerRecords {
+    consumer: Arc<BaseConsumer<CustomContext>>,
+    rebalances: Receiver<RebalanceEvent>,
+    revocation: Arc<Revocation>,
+}
+
+impl Records for ConsumerRecords {
+    fn poll(&mut self) -> Option<Result<Option<Record>, Error>> {
+        // Wake up regularly when idle so interval commits still happen.
+        Some(match self.consumer.poll(Duration::from_millis(100)) {
+            None => Ok(None),
+            Some(Err(e)) => Err(e.into()),
+            Some(Ok(m)) => Ok(Some(Record::new(&m))),
+        })
+    }
+
+    fn rebalances(&mut self) -> Vec<RebalanceEvent> {
+        self.rebalances.try_iter().collect()
+    }
+
+    fn on_revoke(&mut self, revoke: Revoke) {
+        *self.revocation.revoke.lock().unwrap() = Some(revoke);
+    }
+}
+
+impl Committer for ConsumerRecords {
+    fn commit(&self, offsets: &[(String, i32, i64)], mode: CommitMode) -> Result<(), Error> {
+        Committer::commit(&*self.consumer, offsets, mode)
+    }
+}
+
+type Ack = Result<(String, i32, i64), Error>;
+
+/// A pipeline thread for one partition, handling its messages in order.
+struct PartitionWorker {
+    transactions: SyncSender<(Transaction, i64)>,
+    thread: JoinHandle<()>,
+}
+
+impl PartitionWorker {
+    fn send(&self, transaction: Transaction, offset: i64) -> Result<(), Error> {
+        self.transactions
+            .send((transaction, offset))
+            .map_err(|_| format_err!("partition pipeline stopped"))
+    }
+}
+
+struct PartitionWorkers {
+    f: Arc<TransactionHandler>,
+    backlog: usize,
+    workers: HashMap<(String, i32), PartitionWorker>,
+    acks_tx: Sender<Ack>,
+    acks: Receiver<Ack>,
+}
+
+impl PartitionWorkers {
+    fn new(f: Arc<TransactionHandler>, backlog: usize) -> PartitionWorkers {
+        let (acks_tx, acks) = channel();
+        PartitionWorkers {
+            f,
+            backlog,
+            workers: HashMap::new(),
+            acks_tx,
+            acks,
+        }
+    }
+
+    /// The worker for a partition, started if it isn't running yet.
+    fn spawn(&mut self, topic: String, partition: i32) -> &PartitionWorker {
+        let (f, backlog, acks) = (&self.f, self.backlog, &self.acks_tx);
+        self.workers
+            .entry((topic.clone(), partition))
+            .or_insert_with(|| {
+                debug!("Starting pipeline for {} partition {}", topic, partition);
+                let (transactions, rx) = sync_channel::<(Transaction, i64)>(backlog);
+                let (f, acks) = (f.clone(), acks.clone());
+                let thread = thread::spawn(move || {
+                    for (transaction, offset) in rx {
+                        let ack = f(transaction)
+                            .wait()
+                            .map(|_| (topic.clone(), partition, offset));
+                        let failed = ack.is_err();
+                        if acks.send(ack).is_err() || failed {
+                            break;
                         }
-                    };
+                    }
+                });
+                PartitionWorker {
+                    transactions,
+                    thread,
                 }
+            })
+    }
+
+    /// Records every acknowledgement received so far, returning how many there were.
+    fn acked(&self, offsets: &mut OffsetTracker) -> Result<u64, Error> {
+        let mut acked = 0;
+        for ack in self.acks.try_iter() {
+            let (topic, partition, offset) = ack?;
+            offsets.acked(&topic, partition, offset);
+            acked += 1;
+        }
+        Ok(acked)
+    }
+
+    /// Waits for every worker to drain its backlog, then records their acknowledgements.
+    fn stop(&mut self, offsets: &mut OffsetTracker) -> Result<u64, Error> {
+        for ((topic, partition), worker) in self.workers.drain() {
+            debug!("Stopping pipeline for {} partition {}", topic, partition);
+            drop(worker.transactions);
+            let _ = worker.thread.join();
+        }
+        self.acked(offsets)
+    }
+}
+
+/// The partition pipelines and the offsets they have reached, shared with the revoke callback.
+struct InFlight {
+    workers: PartitionWorkers,
+    offsets: OffsetTracker,
+    uncommitted: u64,
+    commits: bool,
+    /// A pipeline failure found while revoking, for `consume` to return.
+    failed: Option<Error>,
+}
+
+impl InFlight {
+    /// Lets the pipelines finish what they were given, then hands over whatever made it through
+    /// before the partitions go. The new owner may see some of it again.
+    fn revoke(&mut self, consumer: &dyn Committer) {
+        if let Err(e) = self.workers.stop(&mut self.offsets) {
+            self.failed = Some(e);
+            return;
+        }
+        if self.commits {
+            commit(consumer, &mut self.offsets, CommitMode::Sync);
+        }
+        self.offsets = OffsetTracker::default();
+        self.uncommitted = 0;
+    }
+}
+
+/// Commits whatever has progressed. A failed commit is retried with the next one, so it only
+/// means more messages may be seen again after a restart.
+fn commit(consumer: &dyn Committer, offsets: &mut OffsetTracker, mode: CommitMode) {
+    let next_offsets = offsets.next_offsets();
+    if next_offsets.is_empty() {
+        return;
+    }
+    match consumer.commit(&next_offsets, mode) {
+        Ok(()) => offsets.committed(&next_offsets),
+        Err(e) => warn!("Failed to commit offsets: {}", e),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CommitModeConfig {
+    Sync,
+    #[default]
+    Async,
+}
+
+impl From<CommitModeConfig> for CommitMode {
+    fn from(mode: CommitModeConfig) -> CommitMode {
+        match mode {
+            CommitModeConfig::Sync => CommitMode::Sync,
+            CommitModeConfig::Async => CommitMode::Async,
+        }
+    }
+}
+
+/// Offsets are committed once either threshold is reached, a threshold of 0 is disabled.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct CommitStrategy {
+    #[serde(default)]
+    mode: CommitModeConfig,
+    #[serde(default = "default_every_messages")]
+    every_messages: u64,
+    #[serde(default = "default_every_ms")]
+    every_ms: u64,
+}
+
+fn default_every_messages() -> u64 {
+    1000
+}
+
+fn default_every_ms() -> u64 {
+    5000
+}
+
+impl Default for CommitStrategy {
+    fn default() -> Self {
+        CommitStrategy {
+            mode: CommitModeConfig::default(),
+            every_messages: default_every_messages(),
+            every_ms: default_every_ms(),
+        }
+    }
+}
+
+impl CommitStrategy {
+    fn due(&self, uncommitted: u64, elapsed: Duration) -> bool {
+        uncommitted > 0
+            && ((self.every_messages != 0 && uncommitted >= self.every_messages)
+                || (self.every_ms != 0 && elapsed >= Duration::from_millis(self.every_ms)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    /// Offsets handed to the pipeline and not yet acknowledged.
+    pending: BTreeSet<i64>,
+    acked: Option<i64>,
+    committed: Option<i64>,
+}
+
+/// Tracks which offsets the pipeline has acknowledged, so only offsets below the oldest
+/// unacknowledged message of a partition are ever committed.
+#[derive(Debug, Default)]
+struct OffsetTracker {
+    partitions: HashMap<(String, i32), PartitionOffsets>,
+}
+
+impl OffsetTracker {
+    fn received(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partition(topic, partition).pending.insert(offset);
+    }
+
+    fn acked(&mut self, topic: &str, partition: i32, offset: i64) {
+        let offsets = self.partition(topic, partition);
+        offsets.pending.remove(&offset);
+        offsets.acked = offsets.acked.max(Some(offset));
+    }
+
+    /// The offset to resume from for each partition that has progressed since the last commit.
+    fn next_offsets(&self) -> Vec<(String, i32, i64)> {
+        let mut next_offsets = Vec::new();
+        for ((topic, partition), offsets) in &self.partitions {
+            let next = match offsets.pending.iter().next() {
+                Some(oldest) => Some(*oldest),
+                None => offsets.acked.map(|acked| acked + 1),
             };
+            if next > offsets.committed {
+                next_offsets.extend(next.map(|next| (topic.clone(), *partition, next)));
+            }
         }
+        next_offsets
+    }
 
-        Ok(())
+    fn committed(&mut self, next_offsets: &[(String, i32, i64)]) {
+        for (topic, partition, offset) in next_offsets {
+            let offsets = self.partition(topic, *partition);
+            offsets.committed = offsets.committed.max(Some(*offset));
+        }
+    }
+
+    fn partition(&mut self, topic: &str, partition: i32) -> &mut PartitionOffsets {
+        self.partitions
+            .entry((topic.to_owned(), partition))
+            .or_default()
     }
 }
 
-#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+/// Record headers keep their own names, the record's position and key are prefixed with `kafka_`.
+fn message_metadata(m: &BorrowedMessage) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+
+    if let Some(headers) = m.headers() {
+        for i in 0..headers.count() {
+            if let Some((name, value)) = headers.get(i) {
+                metadata.insert(name.to_owned(), String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+
+    metadata.insert("kafka_topic".into(), m.topic().to_owned());
+    metadata.insert("kafka_partition".into(), m.partition().to_string());
+    metadata.insert("kafka_offset".into(), m.offset().to_string());
+    if let Some(key) = m.key() {
+        metadata.insert(
+            "kafka_key".into(),
+            String::from_utf8_lossy(key).into_owned(),
+        );
+    }
+    if let Some(timestamp) = m.timestamp().to_millis() {
+        metadata.insert("kafka_timestamp".into(), timestamp.to_string());
+    }
+    if m.payload().is_none() {
+        metadata.insert("kafka_tombstone".into(), "true".into());
+    }
+
+    metadata
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 struct KafkaOut {
-    topic: String,
+    topic: Template,
+    /// Messages are sent unkeyed when this is unset or renders empty.
+    #[serde(default)]
+    key: Option<Template>,
+    /// The partitioner picks a partition when this is unset or renders empty.
+    #[serde(default)]
+    partition: Option<Template>,
+    #[serde(default)]
+    headers: HeaderFilter,
+    /// Maximum number of records of a batch awaiting their delivery report at once.
+    #[serde(default = "default_max_in_flight")]
+    max_in_flight: usize,
+    /// Produce each transaction of the pipeline in a Kafka transaction, which also commits the
+    /// offsets of the messages it came from for the kafka input.
+    #[serde(default)]
+    exactly_once: Option<ExactlyOnce>,
     config: HashMap<String, String>,
 }
 
-#[typetag::serde(name = "kafka")]
-impl Sink for KafkaOut {
-    fn create(&self) -> WriteHandler {
+fn default_max_in_flight() -> usize {
+    1024
+}
+
+/// The kafka input whose offsets are committed, it needs `exactly_once` set as well.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct ExactlyOnce {
+    group_id: String,
+}
+
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for room in the producer's queue before trying a record again.
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Which metadata is forwarded as record headers. Everything is forwarded when `allow` is empty,
+/// names ending in `*` match by prefix and `kafka_*` metadata from the kafka input is never sent.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct HeaderFilter {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl HeaderFilter {
+    fn forwards(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        !name.starts_with("kafka_")
+            && (self.allow.is_empty() || self.allow.iter().any(matches))
+            && !self.deny.iter().any(matches)
+    }
+
+    fn headers(&self, metadata: &HashMap<String, String>) -> Option<OwnedHeaders> {
+        let mut forwarded = metadata.iter().filter(|(k, _)| self.forwards(k)).peekable();
+        forwarded.peek()?;
+        Some(forwarded.fold(OwnedHeaders::new(), |headers, (k, v)| {
+            headers.add(k, v.as_str())
+        }))
+    }
+}
+
+/// The producer as seen by `KafkaOut`, so it can be replaced by a stand-in in tests.
+trait Deliver: Send + Sync {
+    /// Enqueues a record, resolving to its partition and offset once delivered.
+    fn deliver(&self, record: FutureRecord<str, [u8]>) -> BoxFuture<(i32, i64), Error>;
+
+    fn begin_transaction(&self) -> Result<(), Error>;
+
+    /// Commits what was delivered since `begin_transaction`, along with the offsets to resume
+    /// the group's consumer from.
+    fn commit_transaction(
+        &self,
+        offsets: &[(String, i32, i64)],
+        group_id: &str,
+    ) -> Result<(), Error>;
+
+    fn abort_transaction(&self) -> Result<(), Error>;
+}
+
+/// Completes the future of each record once its delivery is reported.
+struct DeliveryContext;
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = Box<oneshot::Sender<KafkaResult<(i32, i64)>>>;
+
+    fn delivery(&self, result: &DeliveryResult, delivered: Self::DeliveryOpaque) {
+        let _ = delivered.send(match result {
+            Ok(m) => Ok((m.partition(), m.offset())),
+            Err((e, _)) => Err(e.clone()),
+        });
+    }
+}
+
+type KafkaProducer = ThreadedProducer<DeliveryContext>;
+
+impl Deliver for KafkaProducer {
+    fn deliver(&self, record: FutureRecord<str, [u8]>) -> BoxFuture<(i32, i64), Error> {
+        let (delivered, delivery) = oneshot::channel();
+        let mut record = BaseRecord {
+            topic: record.topic,
+            partition: record.partition,
+            payload: record.payload,
+            key: record.key,
+            timestamp: record.timestamp,
+            headers: record.headers,
+            delivery_opaque: Box::new(delivered),
+        };
+        // Wait for room rather than failing the record while the queue is full.
+        loop {
+            match self.send(record) {
+                Ok(()) => break,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                    record = r;
+                    thread::sleep(QUEUE_FULL_BACKOFF);
+                }
+                Err((e, _)) => return Box::new(err(e.into())),
+            }
+        }
+
+        Box::new(delivery.then(|delivery| match delivery {
+            Ok(Ok(delivered)) => Ok(delivered),
+            Ok(Err(e)) => Err(Error::from(e)),
+            Err(_) => Err(format_err!("producer dropped before delivery")),
+        }))
+    }
+
+    fn begin_transaction(&self) -> Result<(), Error> {
+        Ok(Producer::begin_transaction(self)?)
+    }
+
+    fn commit_transaction(
+        &self,
+        offsets: &[(String, i32, i64)],
+        group_id: &str,
+    ) -> Result<(), Error> {
+        if !offsets.is_empty() {
+            let consumer = GROUPS
+                .lock()
+                .unwrap()
+                .get(group_id)
+                .and_then(Weak::upgrade)
+                .ok_or_else(|| format_err!("no exactly_once kafka input in group {}", group_id))?;
+            let group = consumer
+                .group_metadata()
+                .ok_or_else(|| format_err!("no metadata for group {}", group_id))?;
+
+            let mut list = TopicPartitionList::new();
+            for (topic, partition, offset) in offsets {
+                list.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+            }
+            self.send_offsets_to_transaction(&list, &group, TRANSACTION_TIMEOUT)?;
+        }
+        Ok(Producer::commit_transaction(self, TRANSACTION_TIMEOUT)?)
+    }
+
+    fn abort_transaction(&self) -> Result<(), Error> {
+        Ok(Producer::abort_transaction(self, TRANSACTION_TIMEOUT)?)
+    }
+}
+
+/// The offset to resume from for each partition the kafka input's messages came from.
+fn consumed_offsets(batches: &[MessageBatch]) -> Vec<(String, i32, i64)> {
+    let mut offsets = BTreeMap::new();
+    for m in batches.iter().flat_map(|b| &b.messages) {
+        let position = (
+            m.metadata.get("kafka_topic"),
+            m.metadata
+                .get("kafka_partition")
+                .and_then(|p| p.parse().ok()),
+            m.metadata
+                .get("kafka_offset")
+                .and_then(|o| o.parse::<i64>().ok()),
+        );
+        if let (Some(topic), Some(partition), Some(offset)) = position {
+            let next = offsets.entry((topic.clone(), partition)).or_insert(0);
+            *next = (*next).max(offset + 1);
+        }
+    }
+    offsets
+        .into_iter()
+        .map(|((topic, partition), offset)| (topic, partition, offset))
+        .collect()
+}
+
+impl KafkaOut {
+    /// Enqueues a record, resolving once its delivery has been reported.
+    fn send(&self, producer: &dyn Deliver, m: Message, batch_size: usize) -> BoxFuture<(), Error> {
+        let render = |template: &Template| template.render_in_batch(&m, batch_size);
+        let topic = render(&self.topic);
+        let key = self.key.as_ref().map(render).filter(|k| !k.is_empty());
+        let partition = match self.partition.as_ref().map(render) {
+            Some(ref p) if !p.is_empty() => match p.parse::<i32>() {
+                Ok(partition) => Some(partition),
+                Err(_) => return Box::new(err(format_err!("invalid partition {:?}", p))),
+            },
+            _ => None,
+        };
+
+        let mut record = FutureRecord::<str, [u8]>::to(&topic).payload(&m.data);
+        if let Some(key) = &key {
+            record = record.key(key);
+        }
+        if let Some(partition) = partition {
+            record = record.partition(partition);
+        }
+        if let Some(headers) = self.headers.headers(&m.metadata) {
+            record = record.headers(headers);
+        }
+
+        Box::new(producer.deliver(record).map(|(partition, offset)| {
+            debug!(
+                "Delivered message to partition {} at offset {}",
+                partition, offset
+            );
+        }))
+    }
+
+    /// Sends every message of a batch, failing once they are all settled if any of them failed.
+    fn send_batch(&self, producer: Arc<dyn Deliver>, batch: MessageBatch) -> BoxFuture<(), Error> {
+        let total = batch.messages.len();
+        let out = Arc::new(self.clone());
+
+        // Failures are collected rather than raised, buffered() loses track of the order of what
+        // comes after an error.
+        let deliveries = stream::iter_ok::<_, Error>(batch.messages)
+            .map(move |m| out.send(&*producer, m, total).then(Ok::<_, Error>))
+            .buffered(self.max_in_flight.max(1))
+            .collect();
+
+        Box::new(deliveries.and_then(move |deliveries| {
+            let mut failures = deliveries.into_iter().filter_map(Result::err);
+            match failures.next() {
+                None => Ok(()),
+                Some(e) => Err(format_err!(
+                    "{} of {} messages failed delivery, first error: {}",
+                    failures.count() + 1,
+                    total,
+                    e
+                )),
+            }
+        }))
+    }
+
+    fn write_handler(&self, producer: Arc<dyn Deliver>) -> WriteHandler {
+        let out = Arc::new(self.clone());
+        // A producer has one transaction open at a time, pipelines of other partitions wait.
+        let transaction = Arc::new(Mutex::new(()));
+
+        Box::new(move |batches| {
+            let (producer, out) = (producer.clone(), out.clone());
+
+            let group_id = match &out.exactly_once {
+                Some(exactly_once) => exactly_once.group_id.clone(),
+                None => {
+                    return Box::new(
+                        batches.for_each(move |batch| out.send_batch(producer.clone(), batch)),
+                    )
+                }
+            };
+
+            let transaction = transaction.clone();
+            Box::new(batches.collect().and_then(move |batches| {
+                let offsets = consumed_offsets(&batches);
+                if offsets.is_empty() && batches.iter().all(|b| b.messages.is_empty()) {
+                    return Ok(());
+                }
+
+                let _transaction = transaction.lock().unwrap();
+                producer.begin_transaction()?;
+                let sent = stream::iter_ok(batches)
+                    .for_each(|batch| out.send_batch(producer.clone(), batch))
+                    .wait();
+                let committed =
+                    sent.and_then(|()| producer.commit_transaction(&offsets, &group_id));
+                if committed.is_err() {
+                    if let Err(e) = producer.abort_transaction() {
+                        warn!("Failed to abort transaction: {}", e);
+                    }
+                }
+                committed
+            }))
+        })
+    }
+
+    fn producer(&self) -> Result<KafkaProducer, Error> {
         let mut config = &mut ClientConfig::new();
 
         for (k, v) in &self.config {
             config = config.set(k, v);
         }
 
-        let producer: FutureProducer = config.create().expect("producer creation error");
-        let topic = self.topic.to_owned();
-
-        Box::new(move |batches| {
-            let (producer, topic) = (producer.clone(), topic.to_owned());
-
-            let result = batches.for_each(move |batch| {
-                for m in batch.messages {
-                    producer
-                        .send(
-                            FutureRecord::to(&topic)
-                                .payload(&m.data)
-                                .key(m.metadata.get("partition_key").unwrap_or(&"0".to_owned())),
-                            -1,
-                        )
-                        .map(move |delivery_status| {
-                            debug!("Delivery status for message {:?} received", delivery_status);
-                            delivery_status
-                        })
-                        .wait()
-                        .unwrap()
-                        .unwrap();
-                }
+        if self.exactly_once.is_some() && !self.config.contains_key("transactional.id") {
+            return Err(format_err!("exactly_once needs a transactional.id"));
+        }
 
-                ok(())
-            });
+        let producer: KafkaProducer = config.create_with_context(DeliveryContext)?;
+        if self.exactly_once.is_some() {
+            producer.init_transactions(TRANSACTION_TIMEOUT)?;
+        }
+        Ok(producer)
+    }
+}
 
-            Box::new(result)
-        })
+#[typetag::serde(name = "kafka")]
+impl Sink for KafkaOut {
+    fn create(&self) -> WriteHandler {
+        match self.producer() {
+            Ok(producer) => self.write_handler(Arc::new(producer)),
+            Err(e) => {
+                let e = e.to_string();
+                Box::new(move |_| Box::new(err(format_err!("{}", e))))
+            }
+        }
     }
 }
 
@@ -154,21 +1211,35 @@ impl Sink for KafkaOut {
 mod tests {
     use super::*;
 
-    use std::sync::mpsc::channel;
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+        sync::mpsc::channel,
+    };
+
+    use futures::future::ok;
 
     use crate::{no_metdata_batches, no_metdata_messages};
 
     macro_rules! source {
-        ( $topics:expr, $config:expr, $consume_count:expr ) => {{
+        ( $topics:expr, $config:expr, $count:expr ) => {{
             $crate::run_source!(
                 KafkaIn {
-                    topics: $topics.into_iter().map(|t|t.to_string()).collect(),
+                    topics: $topics.iter().map(|t| t.to_string()).collect(),
+                    partitions: Vec::new(),
+                    start: None,
+                    stop: None,
                     config: $config
-                        .into_iter()
+                        .iter()
                         .map(|(k, v)| (k.to_string(), v.to_string()))
                         .collect(),
-                    consume_count: $consume_count,
-                }
+                    emit_tombstones: false,
+                    commit: CommitStrategy::default(),
+                    partition_parallel: false,
+                    partition_backlog: default_partition_backlog(),
+                    exactly_once: false,
+                },
+                $count
             )
         }};
     }
@@ -177,9 +1248,14 @@ mod tests {
         ( $topic:expr, $config:expr, $input:expr ) => {{
             $crate::run_sink!(
                 KafkaOut {
-                    topic: $topic.to_string(),
+                    topic: Template::parse(&$topic.to_string()).unwrap(),
+                    key: None,
+                    partition: None,
+                    headers: HeaderFilter::default(),
+                    max_in_flight: default_max_in_flight(),
+                    exactly_once: None,
                     config: $config
-                        .into_iter()
+                        .iter()
                         .map(|(k, v)| (k.to_string(), v.to_string()))
                         .collect(),
                 },
@@ -188,6 +1264,8 @@ mod tests {
         }};
     }
 
+    // Tests talking to a broker are ignored by default, run them against docker-compose with
+    // `cargo test -- --ignored`.
     const SOURCE_CONFIG: [(&str, &str); 4] = [
         ("bootstrap.servers", "localhost:9092"),
         ("message.timeout.ms", "5000"),
@@ -201,6 +1279,7 @@ mod tests {
     ];
 
     #[test]
+    #[ignore]
     fn sink_source_kafka_message_test() {
         let topic = uuid::Uuid::new_v4();
 
@@ -208,21 +1287,1156 @@ mod tests {
 
         sink!(topic, SINK_CONFIG, messages.clone());
 
-        assert_eq!(
-            source!([topic], SOURCE_CONFIG, 1),
-            messages,
-        )
+        let batches = source!([topic], SOURCE_CONFIG, 1);
+        assert_eq!(batches[0].messages[0].data, messages[0].messages[0].data);
+
+        let metadata = &batches[0].messages[0].metadata;
+        assert_eq!(metadata["kafka_topic"], topic.to_string());
+        assert_eq!(metadata["kafka_partition"], "0");
+        assert_eq!(metadata["kafka_offset"], "0");
+        assert!(!metadata.contains_key("kafka_key"));
+        assert!(metadata.contains_key("kafka_timestamp"));
     }
 
     #[test]
+    #[ignore]
     fn sink_source_kafka_message_test_no_equal() {
         let topic = uuid::Uuid::new_v4();
 
-        sink!(topic, SINK_CONFIG, no_metdata_batches![no_metdata_messages![b"hello,world,cheese"]]);
+        sink!(
+            topic,
+            SINK_CONFIG,
+            no_metdata_batches![no_metdata_messages![b"hello,world,cheese"]]
+        );
 
         assert_ne!(
             source!([topic], SOURCE_CONFIG, 1),
             no_metdata_batches![no_metdata_messages![b"cheese"]],
         )
     }
+
+    #[test]
+    #[ignore]
+    fn source_kafka_tombstone_headers_test() {
+        let topic = uuid::Uuid::new_v4().to_string();
+
+        let mut config = ClientConfig::new();
+        for (k, v) in &SINK_CONFIG {
+            config.set(*k, *v);
+        }
+        let producer: KafkaProducer = config.create_with_context(DeliveryContext).unwrap();
+        producer
+            .deliver(
+                FutureRecord::<str, [u8]>::to(&topic)
+                    .key("cheese")
+                    .headers(OwnedHeaders::new().add("origin", "brie")),
+            )
+            .wait()
+            .unwrap();
+
+        let batches = crate::run_source!(
+            KafkaIn {
+                topics: vec![topic],
+                partitions: Vec::new(),
+                start: None,
+                stop: None,
+                config: SOURCE_CONFIG
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                emit_tombstones: true,
+                commit: CommitStrategy::default(),
+                partition_parallel: false,
+                partition_backlog: default_partition_backlog(),
+                exactly_once: false,
+            },
+            1
+        );
+
+        let message = &batches[0].messages[0];
+        assert!(message.data.is_empty());
+        assert_eq!(message.metadata["kafka_tombstone"], "true");
+        assert_eq!(message.metadata["kafka_key"], "cheese");
+        assert_eq!(message.metadata["origin"], "brie");
+    }
+
+    #[test]
+    #[ignore]
+    fn sink_source_kafka_dynamic_record_test() {
+        let topic = uuid::Uuid::new_v4().to_string();
+
+        let mut message = Message {
+            data: b"cheese".to_vec(),
+            ..Default::default()
+        };
+        for (k, v) in &[
+            ("topic", topic.as_str()),
+            ("id", "brie"),
+            ("origin", "france"),
+            ("secret", "bacon"),
+            ("kafka_offset", "12"),
+        ] {
+            message.metadata.insert(k.to_string(), v.to_string());
+        }
+
+        crate::run_sink!(
+            KafkaOut {
+                topic: Template::parse("${! metadata:topic }").unwrap(),
+                key: Some(Template::parse("${! metadata:id }").unwrap()),
+                partition: Some(Template::parse("0").unwrap()),
+                headers: HeaderFilter {
+                    allow: vec![],
+                    deny: vec!["secret".into(), "top*".into()],
+                },
+                max_in_flight: 1,
+                exactly_once: None,
+                config: SINK_CONFIG
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            },
+            no_metdata_batches![vec![message]]
+        );
+
+        let batches = source!([topic], SOURCE_CONFIG, 1);
+        let metadata = &batches[0].messages[0].metadata;
+        assert_eq!(metadata["kafka_key"], "brie");
+        assert_eq!(metadata["kafka_offset"], "0");
+        assert_eq!(metadata["origin"], "france");
+        assert!(!metadata.contains_key("secret"));
+        assert!(!metadata.contains_key("topic"));
+        assert_eq!(metadata["id"], "brie");
+    }
+
+    #[test]
+    fn sink_kafka_batch_delivery_error_test() {
+        let sink = KafkaOut {
+            topic: Template::parse("cheese").unwrap(),
+            key: None,
+            partition: None,
+            headers: HeaderFilter::default(),
+            max_in_flight: 2,
+            exactly_once: None,
+            config: [
+                ("bootstrap.servers", "localhost:1"),
+                ("message.timeout.ms", "100"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        };
+
+        let batches = no_metdata_batches![no_metdata_messages![b"brie", b"feta", b"goat"]];
+        let result = sink.create()(Box::new(stream::iter_ok(batches))).wait();
+
+        let e = result.unwrap_err().to_string();
+        assert!(e.starts_with("3 of 3 messages failed delivery"), "{}", e);
+    }
+
+    #[test]
+    #[ignore]
+    fn sink_source_kafka_pipelined_test() {
+        let topic = uuid::Uuid::new_v4();
+
+        let messages = (0..1000)
+            .map(|i| Message {
+                data: i.to_string().into_bytes(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        sink!(topic, SINK_CONFIG, no_metdata_batches![messages.clone()]);
+
+        let consumed = source!([topic], SOURCE_CONFIG, 1000)
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .map(|m| m.data)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            consumed,
+            messages.into_iter().map(|m| m.data).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn offset_tracker_test() {
+        let mut offsets = OffsetTracker::default();
+        offsets.received("cheese", 0, 10);
+        offsets.received("cheese", 0, 11);
+        offsets.received("cheese", 0, 14);
+        offsets.received("cheese", 1, 3);
+
+        offsets.acked("cheese", 0, 11);
+        offsets.acked("cheese", 1, 3);
+        let mut next = offsets.next_offsets();
+        next.sort();
+        assert_eq!(
+            next,
+            vec![("cheese".to_owned(), 0, 10), ("cheese".to_owned(), 1, 4)]
+        );
+
+        // Until they are committed the same offsets are due again.
+        assert_eq!(offsets.next_offsets().len(), 2);
+        offsets.committed(&next);
+        assert_eq!(offsets.next_offsets(), vec![]);
+
+        offsets.acked("cheese", 0, 10);
+        assert_eq!(offsets.next_offsets(), vec![("cheese".to_owned(), 0, 14)]);
+        offsets.acked("cheese", 0, 14);
+        assert_eq!(offsets.next_offsets(), vec![("cheese".to_owned(), 0, 15)]);
+    }
+
+    #[test]
+    fn commit_strategy_test() {
+        let strategy = CommitStrategy {
+            mode: CommitModeConfig::Async,
+            every_messages: 10,
+            every_ms: 1000,
+        };
+        assert!(!strategy.due(0, Duration::from_secs(10)));
+        assert!(!strategy.due(9, Duration::from_millis(999)));
+        assert!(strategy.due(10, Duration::from_millis(0)));
+        assert!(strategy.due(1, Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn consumer_config_test() {
+        let source = |config: &[(&str, &str)]| KafkaIn {
+            config: config
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+            ..KafkaIn::default()
+        };
+
+        let config = source(&SOURCE_CONFIG).consumer_config().unwrap();
+        assert_eq!(config["group.id"], "test-consumer");
+        assert_eq!(config["enable.auto.commit"], "false");
+        assert_eq!(config["enable.auto.offset.store"], "false");
+        assert_eq!(config["statistics.interval.ms"], "5000");
+        let config = source(&[("statistics.interval.ms", "0")])
+            .consumer_config()
+            .unwrap();
+        assert_eq!(config["statistics.interval.ms"], "0");
+
+        let e = source(&[("enable.auto.offset.store", "true")])
+            .consumer_config()
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "enable.auto.offset.store can't be true, offsets are committed once messages are \
+             delivered"
+        );
+
+        // Bad config fails the input rather than the process.
+        let e = source(&[("bootstrap.servers", "localhost:1"), ("cheese", "brie")])
+            .start(Box::new(|_| Box::new(ok(Vec::new()))))
+            .unwrap_err();
+        assert!(
+            e.to_string().starts_with("failed to create consumer"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn partition_workers_test() {
+        let f: TransactionHandler = Box::new(|transaction| {
+            if transaction.batch.messages[0].data == b"slow" {
+                thread::sleep(Duration::from_millis(200));
+            }
+            Box::new(ok(vec![transaction.batch]))
+        });
+        let mut workers = PartitionWorkers::new(Arc::new(f), 10);
+        let mut offsets = OffsetTracker::default();
+
+        let transaction = |data: &[u8]| Transaction {
+            batch: no_metdata_batches![no_metdata_messages![data]].remove(0),
+        };
+        for (partition, offset, data) in &[(0, 0, &b"slow"[..]), (0, 1, b"brie"), (1, 0, b"feta")] {
+            offsets.received("cheese", *partition, *offset);
+            workers
+                .spawn("cheese".into(), *partition)
+                .send(transaction(data), *offset)
+                .unwrap();
+        }
+
+        // The slow message holds up its own partition, not the other one.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(workers.acked(&mut offsets).unwrap(), 1);
+        let mut next = offsets.next_offsets();
+        next.sort();
+        assert_eq!(
+            next,
+            vec![("cheese".to_owned(), 0, 0), ("cheese".to_owned(), 1, 1)]
+        );
+        offsets.committed(&next);
+
+        assert_eq!(workers.stop(&mut offsets).unwrap(), 2);
+        assert_eq!(offsets.next_offsets(), vec![("cheese".to_owned(), 0, 2)]);
+    }
+
+    #[test]
+    #[ignore]
+    fn source_kafka_partition_parallel_test() {
+        // Created with two partitions by docker-compose, auto created topics only get one.
+        let topic = "cheese-partitions".to_owned();
+
+        let mut config = ClientConfig::new();
+        for (k, v) in &SOURCE_CONFIG {
+            config.set(*k, *v);
+        }
+        let consumer: BaseConsumer = config.create().unwrap();
+        let starts = (0..2)
+            .map(|partition| {
+                consumer
+                    .fetch_watermarks(&topic, partition, METADATA_TIMEOUT)
+                    .unwrap()
+                    .1
+            })
+            .collect::<Vec<_>>();
+
+        let messages = (0..20)
+            .map(|i| {
+                let mut message = Message {
+                    data: i.to_string().into_bytes(),
+                    ..Default::default()
+                };
+                message
+                    .metadata
+                    .insert("partition".into(), (i % 2).to_string());
+                message
+            })
+            .collect::<Vec<_>>();
+        crate::run_sink!(
+            KafkaOut {
+                topic: Template::parse(&topic).unwrap(),
+                key: None,
+                partition: Some(Template::parse("${! metadata:partition }").unwrap()),
+                headers: HeaderFilter::default(),
+                max_in_flight: default_max_in_flight(),
+                exactly_once: None,
+                config: SINK_CONFIG
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            },
+            no_metdata_batches![messages]
+        );
+
+        // Earlier runs left their messages on the topic, only this run's are checked.
+        let batches = crate::run_source!(KafkaIn {
+            topics: Vec::new(),
+            partitions: (0..2)
+                .map(|partition| TopicPartition {
+                    topic: topic.clone(),
+                    partition,
+                })
+                .collect(),
+            start: Some(StartPosition::Beginning),
+            stop: Some(StopPosition::HighWaterMark),
+            config: SOURCE_CONFIG
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            emit_tombstones: false,
+            commit: CommitStrategy::default(),
+            partition_parallel: true,
+            partition_backlog: default_partition_backlog(),
+            exactly_once: false,
+        });
+
+        let mut partitions: HashMap<String, Vec<i32>> = HashMap::new();
+        for m in batches.into_iter().flat_map(|b| b.messages) {
+            let partition: usize = m.metadata["kafka_partition"].parse().unwrap();
+            let offset: i64 = m.metadata["kafka_offset"].parse().unwrap();
+            if offset < starts[partition] {
+                continue;
+            }
+            partitions
+                .entry(m.metadata["kafka_partition"].clone())
+                .or_default()
+                .push(String::from_utf8(m.data).unwrap().parse().unwrap());
+        }
+        assert_eq!(partitions["0"], (0..20).step_by(2).collect::<Vec<_>>());
+        assert_eq!(partitions["1"], (1..20).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn consumer_lag_test() {
+        let topic = uuid::Uuid::new_v4().to_string();
+        let lag = |partition: i32| {
+            prometheus::gather()
+                .into_iter()
+                .filter(|family| family.get_name() == "nekton_kafka_consumer_lag")
+                .flat_map(|family| family.get_metric().to_vec())
+                .find(|metric| {
+                    let labels = metric.get_label();
+                    labels.iter().any(|l| l.get_value() == topic)
+                        && labels
+                            .iter()
+                            .any(|l| l.get_value() == partition.to_string())
+                })
+                .map(|metric| metric.get_gauge().get_value() as i64)
+        };
+
+        let context = CustomContext::new(channel().0, Arc::default());
+        context.assigned(vec![(topic.clone(), 0), (topic.clone(), 1)]);
+
+        record_lag(&topic, 0, 100, 40);
+        // Nothing committed yet.
+        record_lag(&topic, 1, 100, -1001);
+        assert_eq!(lag(0), Some(60));
+        assert_eq!(lag(1), None);
+
+        record_lag(&topic, 0, 120, 120);
+        assert_eq!(lag(0), Some(0));
+
+        context.assigned(Vec::new());
+        assert_eq!(lag(0), None);
+    }
+
+    #[test]
+    fn stop_offsets_test() {
+        let mut stops = StopOffsets {
+            remaining: vec![
+                (("cheese".to_owned(), 0), 3),
+                (("cheese".to_owned(), 1), 10),
+            ]
+            .into_iter()
+            .collect(),
+            timestamp: Some(5000),
+        };
+
+        assert!(stops.admit("cheese", 0, 1, Some(1000)));
+        assert!(stops.admit("cheese", 0, 2, Some(1000)));
+        assert!(!stops.admit("cheese", 0, 3, Some(1000)));
+        assert!(!stops.finished());
+
+        assert!(stops.admit("cheese", 1, 0, None));
+        assert!(!stops.admit("cheese", 1, 1, Some(5000)));
+        assert!(!stops.admit("cheese", 1, 2, Some(1000)));
+        assert!(stops.finished());
+    }
+
+    fn replay(
+        topic: &str,
+        start: Option<StartPosition>,
+        stop: Option<StopPosition>,
+    ) -> Vec<Vec<u8>> {
+        crate::run_source!(KafkaIn {
+            topics: Vec::new(),
+            partitions: vec![TopicPartition {
+                topic: topic.to_owned(),
+                partition: 0,
+            }],
+            start,
+            stop,
+            config: SOURCE_CONFIG
+                .iter()
+                .filter(|(k, _)| *k != "group.id")
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            emit_tombstones: false,
+            commit: CommitStrategy::default(),
+            partition_parallel: false,
+            partition_backlog: default_partition_backlog(),
+            exactly_once: false,
+        })
+        .into_iter()
+        .flat_map(|b| b.messages)
+        .map(|m| m.data)
+        .collect()
+    }
+
+    #[test]
+    #[ignore]
+    fn source_kafka_replay_offset_test() {
+        let topic = uuid::Uuid::new_v4().to_string();
+
+        let messages = (0..5)
+            .map(|i| Message {
+                data: vec![i],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        sink!(topic, SINK_CONFIG, no_metdata_batches![messages]);
+
+        assert_eq!(
+            replay(
+                &topic,
+                Some(StartPosition::Offset(2)),
+                Some(StopPosition::HighWaterMark)
+            ),
+            vec![vec![2], vec![3], vec![4]]
+        );
+        assert_eq!(
+            replay(
+                &topic,
+                Some(StartPosition::End),
+                Some(StopPosition::HighWaterMark)
+            ),
+            Vec::<Vec<u8>>::new()
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn source_kafka_replay_timestamp_test() {
+        let topic = uuid::Uuid::new_v4().to_string();
+
+        let mut config = ClientConfig::new();
+        for (k, v) in &SINK_CONFIG {
+            config.set(*k, *v);
+        }
+        let producer: KafkaProducer = config.create_with_context(DeliveryContext).unwrap();
+        for timestamp in &[1000, 2000, 3000, 4000] {
+            let data = timestamp.to_string();
+            producer
+                .deliver(
+                    FutureRecord::<str, [u8]>::to(&topic)
+                        .payload(data.as_bytes())
+                        .timestamp(*timestamp),
+                )
+                .wait()
+                .unwrap();
+        }
+
+        assert_eq!(
+            replay(
+                &topic,
+                Some(StartPosition::Timestamp(1500)),
+                Some(StopPosition::Timestamp(3000))
+            ),
+            vec![b"2000".to_vec()]
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn source_kafka_regex_topics_test() {
+        let prefix = uuid::Uuid::new_v4();
+        let topic = format!("{}-events", prefix);
+
+        sink!(
+            topic,
+            SINK_CONFIG,
+            no_metdata_batches![no_metdata_messages![b"cheese"]]
+        );
+
+        let batches = source!([format!("^{}-.*", prefix)], SOURCE_CONFIG, 1);
+        assert_eq!(batches[0].messages[0].data, b"cheese");
+        assert_eq!(batches[0].messages[0].metadata["kafka_topic"], topic);
+    }
+
+    #[test]
+    #[ignore]
+    fn sink_source_kafka_exactly_once_test() {
+        let input = uuid::Uuid::new_v4().to_string();
+        let output = uuid::Uuid::new_v4().to_string();
+        let group_id = uuid::Uuid::new_v4().to_string();
+
+        sink!(
+            input,
+            SINK_CONFIG,
+            no_metdata_batches![no_metdata_messages![b"brie", b"feta", b"edam"]]
+        );
+
+        let mut config: HashMap<_, _> = SOURCE_CONFIG
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        config.insert("group.id".into(), group_id.clone());
+        let source = KafkaIn {
+            partitions: vec![TopicPartition {
+                topic: input.clone(),
+                partition: 0,
+            }],
+            stop: Some(StopPosition::HighWaterMark),
+            config: config.clone(),
+            exactly_once: true,
+            ..KafkaIn::default()
+        };
+
+        let mut sink_config: HashMap<_, _> = SINK_CONFIG
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        sink_config.insert("transactional.id".into(), uuid::Uuid::new_v4().to_string());
+        let write = KafkaOut {
+            topic: Template::parse(&output).unwrap(),
+            key: None,
+            partition: None,
+            headers: HeaderFilter::default(),
+            max_in_flight: default_max_in_flight(),
+            exactly_once: Some(ExactlyOnce {
+                group_id: group_id.clone(),
+            }),
+            config: sink_config,
+        }
+        .create();
+
+        source
+            .start(Box::new(move |transaction| {
+                let batches = vec![transaction.batch];
+                let written = write(Box::new(stream::iter_ok(batches.clone()))).wait();
+                Box::new(futures::future::result(written.map(|()| batches)))
+            }))
+            .unwrap();
+
+        // The input's offsets were committed by the output's transactions.
+        let mut client = ClientConfig::new();
+        for (k, v) in &config {
+            client.set(k, v);
+        }
+        let consumer: BaseConsumer = client.create().unwrap();
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(&input, 0);
+        let committed = consumer
+            .committed_offsets(partitions, METADATA_TIMEOUT)
+            .unwrap();
+        assert_eq!(
+            committed.find_partition(&input, 0).unwrap().offset(),
+            Offset::Offset(3)
+        );
+
+        let mut assignment = TopicPartitionList::new();
+        assignment
+            .add_partition_offset(&output, 0, Offset::Beginning)
+            .unwrap();
+        consumer.assign(&assignment).unwrap();
+        let mut received = Vec::new();
+        let started = Instant::now();
+        while received.len() < 3 && started.elapsed() < METADATA_TIMEOUT {
+            if let Some(m) = consumer.poll(Duration::from_millis(100)) {
+                received.push(m.unwrap().payload().unwrap().to_vec());
+            }
+        }
+        assert_eq!(
+            received,
+            vec![b"brie".to_vec(), b"feta".to_vec(), b"edam".to_vec()]
+        );
+    }
+
+    /// Plays back a scripted consumer session in place of a broker, keeping the commits made.
+    #[derive(Default)]
+    struct StandInConsumer {
+        events: VecDeque<Event>,
+        rebalances: Vec<RebalanceEvent>,
+        revoke: Option<Revoke>,
+        failing_commits: Cell<usize>,
+        commits: RefCell<Vec<Commit>>,
+    }
+
+    /// Committed offsets, and whether the commit was synchronous.
+    type Commit = (Vec<(String, i32, i64)>, bool);
+
+    enum Event {
+        Record(i32, i64, &'static [u8]),
+        Rebalance(RebalanceEvent),
+        /// Runs the revoke callback inside the poll, as librdkafka does.
+        Revoke,
+        Error,
+    }
+
+    impl StandInConsumer {
+        fn new(events: Vec<Event>) -> StandInConsumer {
+            StandInConsumer {
+                events: events.into(),
+                ..Default::default()
+            }
+        }
+
+        /// Successful commits, with their offsets sorted.
+        fn commits(&self) -> Vec<Commit> {
+            let mut commits = self.commits.borrow().clone();
+            for (offsets, _) in &mut commits {
+                offsets.sort();
+            }
+            commits
+        }
+    }
+
+    impl Records for StandInConsumer {
+        fn poll(&mut self) -> Option<Result<Option<Record>, Error>> {
+            loop {
+                match self.events.pop_front()? {
+                    Event::Rebalance(rebalance) => self.rebalances.push(rebalance),
+                    Event::Revoke => {
+                        let mut revoke = self.revoke.take().unwrap();
+                        revoke(self);
+                        self.revoke = Some(revoke);
+                    }
+                    Event::Error => return Some(Err(format_err!("all brokers down"))),
+                    Event::Record(partition, offset, data) => {
+                        return Some(Ok(Some(Record {
+                            topic: "cheese".into(),
+                            partition,
+                            offset,
+                            timestamp: None,
+                            payload: Some(data.to_vec()),
+                            metadata: HashMap::new(),
+                        })))
+                    }
+                }
+            }
+        }
+
+        fn rebalances(&mut self) -> Vec<RebalanceEvent> {
+            self.rebalances.drain(..).collect()
+        }
+
+        fn on_revoke(&mut self, revoke: Revoke) {
+            self.revoke = Some(revoke);
+        }
+    }
+
+    impl Committer for StandInConsumer {
+        fn commit(&self, offsets: &[(String, i32, i64)], mode: CommitMode) -> Result<(), Error> {
+            if self.failing_commits.get() > 0 {
+                self.failing_commits.set(self.failing_commits.get() - 1);
+                return Err(format_err!("coordinator not available"));
+            }
+            let sync = matches!(mode, CommitMode::Sync);
+            self.commits.borrow_mut().push((offsets.to_vec(), sync));
+            Ok(())
+        }
+    }
+
+    fn stand_in_source(every_messages: u64, partition_parallel: bool) -> KafkaIn {
+        KafkaIn {
+            topics: vec!["cheese".into()],
+            partitions: Vec::new(),
+            start: None,
+            stop: None,
+            config: vec![("group.id".to_owned(), "test-consumer".to_owned())]
+                .into_iter()
+                .collect(),
+            emit_tombstones: false,
+            commit: CommitStrategy {
+                mode: CommitModeConfig::Async,
+                every_messages,
+                every_ms: 60_000,
+            },
+            partition_parallel,
+            partition_backlog: default_partition_backlog(),
+            exactly_once: false,
+        }
+    }
+
+    /// Keeps whatever reaches the pipeline, failing on `bacon`.
+    fn pipeline() -> (TransactionHandler, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let r = received.clone();
+        let f: TransactionHandler = Box::new(move |transaction| {
+            let data = transaction.batch.messages[0].data.clone();
+            if data == b"bacon" {
+                return Box::new(err(format_err!("not cheese")));
+            }
+            r.lock().unwrap().push(data);
+            Box::new(ok(vec![transaction.batch]))
+        });
+        (f, received)
+    }
+
+    #[test]
+    fn source_kafka_commit_interval_test() {
+        let mut consumer = StandInConsumer::new(
+            (0..5)
+                .map(|offset| Event::Record(0, offset, b"brie"))
+                .collect(),
+        );
+        let (f, received) = pipeline();
+
+        stand_in_source(2, false)
+            .consume(&mut consumer, f, None)
+            .unwrap();
+
+        assert_eq!(received.lock().unwrap().len(), 5);
+        assert_eq!(
+            consumer.commits(),
+            vec![
+                (vec![("cheese".to_owned(), 0, 2)], false),
+                (vec![("cheese".to_owned(), 0, 4)], false),
+                (vec![("cheese".to_owned(), 0, 5)], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn source_kafka_commit_failure_test() {
+        let mut consumer = StandInConsumer::new(vec![
+            Event::Record(0, 0, b"brie"),
+            Event::Record(0, 1, b"feta"),
+        ]);
+        consumer.failing_commits.set(1);
+        let (f, _) = pipeline();
+
+        stand_in_source(1, false)
+            .consume(&mut consumer, f, None)
+            .unwrap();
+
+        // The failed commit is picked up by the next one.
+        assert_eq!(
+            consumer.commits(),
+            vec![(vec![("cheese".to_owned(), 0, 2)], false)]
+        );
+    }
+
+    #[test]
+    fn source_kafka_pipeline_error_test() {
+        let mut consumer = StandInConsumer::new(vec![
+            Event::Record(0, 0, b"brie"),
+            Event::Record(0, 1, b"bacon"),
+            Event::Record(0, 2, b"feta"),
+        ]);
+        let (f, received) = pipeline();
+
+        let result = stand_in_source(1000, false).consume(&mut consumer, f, None);
+
+        assert_eq!(result.unwrap_err().to_string(), "not cheese");
+        assert_eq!(*received.lock().unwrap(), vec![b"brie".to_vec()]);
+        assert!(consumer.commits().is_empty());
+
+        let mut consumer = StandInConsumer::new(vec![Event::Record(0, 0, b"brie"), Event::Error]);
+        let (f, _) = pipeline();
+
+        let result = stand_in_source(1000, false).consume(&mut consumer, f, None);
+        assert_eq!(result.unwrap_err().to_string(), "all brokers down");
+    }
+
+    #[test]
+    fn source_kafka_rebalance_test() {
+        let partitions = |partitions: &[i32]| {
+            let partitions = partitions.iter().map(|p| ("cheese".to_owned(), *p));
+            Event::Rebalance(RebalanceEvent::Assigned(partitions.collect()))
+        };
+        let mut consumer = StandInConsumer::new(vec![
+            partitions(&[0, 1]),
+            Event::Record(0, 0, b"brie"),
+            Event::Record(1, 0, b"feta"),
+            Event::Record(0, 1, b"goat"),
+            Event::Revoke,
+            partitions(&[0]),
+            Event::Record(0, 2, b"edam"),
+        ]);
+        let (f, received) = pipeline();
+
+        stand_in_source(1000, true)
+            .consume(&mut consumer, f, None)
+            .unwrap();
+
+        // Everything handled before the revoke is committed before giving the partitions up.
+        assert_eq!(
+            consumer.commits(),
+            vec![
+                (
+                    vec![("cheese".to_owned(), 0, 2), ("cheese".to_owned(), 1, 1)],
+                    true
+                ),
+                (vec![("cheese".to_owned(), 0, 3)], true),
+            ]
+        );
+
+        let mut received = received.lock().unwrap().clone();
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                b"brie".to_vec(),
+                b"edam".to_vec(),
+                b"feta".to_vec(),
+                b"goat".to_vec()
+            ]
+        );
+    }
+
+    /// Acknowledges records in place of a broker, failing any `bacon`.
+    #[derive(Default)]
+    struct StandInProducer {
+        sent: Mutex<Vec<Sent>>,
+        transactions: Mutex<Vec<TransactionEvent>>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum TransactionEvent {
+        Begin,
+        /// The offsets committed, the group they are committed for and how many records were
+        /// sent in the transaction.
+        Commit(Vec<(String, i32, i64)>, String, usize),
+        Abort,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Sent {
+        topic: String,
+        key: Option<String>,
+        partition: Option<i32>,
+        headers: Vec<(String, String)>,
+        payload: Vec<u8>,
+    }
+
+    impl Deliver for StandInProducer {
+        fn deliver(&self, record: FutureRecord<str, [u8]>) -> BoxFuture<(i32, i64), Error> {
+            if record.payload == Some(&b"bacon"[..]) {
+                return Box::new(err(format_err!("message timed out")));
+            }
+
+            let mut headers = Vec::new();
+            if let Some(h) = &record.headers {
+                for (k, v) in (0..h.count()).filter_map(|i| h.get(i)) {
+                    headers.push((k.to_owned(), String::from_utf8_lossy(v).into_owned()));
+                }
+            }
+            headers.sort();
+
+            let mut sent = self.sent.lock().unwrap();
+            sent.push(Sent {
+                topic: record.topic.to_owned(),
+                key: record.key.map(str::to_owned),
+                partition: record.partition,
+                headers,
+                payload: record.payload.map(<[u8]>::to_vec).unwrap_or_default(),
+            });
+            Box::new(ok((record.partition.unwrap_or(0), sent.len() as i64 - 1)))
+        }
+
+        fn begin_transaction(&self) -> Result<(), Error> {
+            self.transactions
+                .lock()
+                .unwrap()
+                .push(TransactionEvent::Begin);
+            Ok(())
+        }
+
+        fn commit_transaction(
+            &self,
+            offsets: &[(String, i32, i64)],
+            group_id: &str,
+        ) -> Result<(), Error> {
+            let sent = self.sent.lock().unwrap().len();
+            self.transactions
+                .lock()
+                .unwrap()
+                .push(TransactionEvent::Commit(
+                    offsets.to_vec(),
+                    group_id.to_owned(),
+                    sent,
+                ));
+            Ok(())
+        }
+
+        fn abort_transaction(&self) -> Result<(), Error> {
+            self.transactions
+                .lock()
+                .unwrap()
+                .push(TransactionEvent::Abort);
+            Ok(())
+        }
+    }
+
+    fn stand_in_sink(key: Option<&str>, partition: Option<&str>) -> KafkaOut {
+        KafkaOut {
+            topic: Template::parse("${! metadata:topic }").unwrap(),
+            key: key.map(|k| Template::parse(k).unwrap()),
+            partition: partition.map(|p| Template::parse(p).unwrap()),
+            headers: HeaderFilter {
+                allow: vec![],
+                deny: vec!["secret".into(), "top*".into()],
+            },
+            max_in_flight: 2,
+            exactly_once: None,
+            config: HashMap::new(),
+        }
+    }
+
+    fn deliver(sink: &KafkaOut, messages: Vec<Message>) -> (Result<(), Error>, Vec<Sent>) {
+        let producer = Arc::new(StandInProducer::default());
+        let batches = no_metdata_batches![messages];
+        let result =
+            sink.write_handler(producer.clone())(Box::new(stream::iter_ok(batches))).wait();
+        let sent = producer.sent.lock().unwrap().drain(..).collect();
+        (result, sent)
+    }
+
+    fn message(data: &[u8], metadata: &[(&str, &str)]) -> Message {
+        Message {
+            data: data.to_vec(),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn sink_kafka_dynamic_record_test() {
+        let metadata = [
+            ("topic", "cheeses"),
+            ("id", "brie"),
+            ("origin", "france"),
+            ("secret", "bacon"),
+            ("kafka_offset", "12"),
+        ];
+        let sink = stand_in_sink(Some("${! metadata:id }"), Some("${! metadata:partition }"));
+
+        let (result, sent) = deliver(
+            &sink,
+            vec![
+                message(b"brie", &metadata),
+                message(b"feta", &[("topic", "cheeses"), ("partition", "3")]),
+            ],
+        );
+
+        result.unwrap();
+        assert_eq!(
+            sent,
+            vec![
+                Sent {
+                    topic: "cheeses".into(),
+                    key: Some("brie".into()),
+                    partition: None,
+                    headers: vec![
+                        ("id".into(), "brie".into()),
+                        ("origin".into(), "france".into())
+                    ],
+                    payload: b"brie".to_vec(),
+                },
+                Sent {
+                    topic: "cheeses".into(),
+                    key: None,
+                    partition: Some(3),
+                    headers: vec![("partition".into(), "3".into())],
+                    payload: b"feta".to_vec(),
+                },
+            ]
+        );
+
+        let (result, sent) = deliver(
+            &sink,
+            vec![message(
+                b"brie",
+                &[("topic", "cheeses"), ("partition", "first")],
+            )],
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "1 of 1 messages failed delivery, first error: invalid partition \"first\""
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn sink_kafka_delivery_failure_test() {
+        let topic = [("topic", "cheeses")];
+        let (result, sent) = deliver(
+            &stand_in_sink(None, None),
+            vec![
+                message(b"brie", &topic),
+                message(b"bacon", &topic),
+                message(b"feta", &topic),
+                message(b"bacon", &topic),
+            ],
+        );
+
+        // The rest of the batch is still sent, but the batch as a whole fails.
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "2 of 4 messages failed delivery, first error: message timed out"
+        );
+        assert_eq!(
+            sent.into_iter().map(|s| s.payload).collect::<Vec<_>>(),
+            vec![b"brie".to_vec(), b"feta".to_vec()]
+        );
+    }
+
+    fn consumed(data: &[u8], partition: &str, offset: &str) -> Message {
+        message(
+            data,
+            &[
+                ("topic", "cheeses"),
+                ("kafka_topic", "cheese"),
+                ("kafka_partition", partition),
+                ("kafka_offset", offset),
+            ],
+        )
+    }
+
+    #[test]
+    fn sink_kafka_exactly_once_test() {
+        let mut sink = stand_in_sink(None, None);
+        sink.exactly_once = Some(ExactlyOnce {
+            group_id: "cheeses".into(),
+        });
+        let producer = Arc::new(StandInProducer::default());
+        let write = sink.write_handler(producer.clone());
+
+        let batches = no_metdata_batches![
+            vec![consumed(b"brie", "0", "4"), consumed(b"feta", "1", "2")],
+            vec![consumed(b"edam", "0", "5")]
+        ];
+        write(Box::new(stream::iter_ok(batches))).wait().unwrap();
+
+        let batches = no_metdata_batches![vec![
+            consumed(b"goat", "0", "6"),
+            consumed(b"bacon", "0", "7")
+        ]];
+        assert_eq!(
+            write(Box::new(stream::iter_ok(batches)))
+                .wait()
+                .unwrap_err()
+                .to_string(),
+            "1 of 2 messages failed delivery, first error: message timed out"
+        );
+
+        // Each transaction of the pipeline commits the offsets after its messages, or nothing.
+        assert_eq!(
+            producer
+                .transactions
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>(),
+            vec![
+                TransactionEvent::Begin,
+                TransactionEvent::Commit(
+                    vec![("cheese".into(), 0, 6), ("cheese".into(), 1, 3)],
+                    "cheeses".into(),
+                    3
+                ),
+                TransactionEvent::Begin,
+                TransactionEvent::Abort,
+            ]
+        );
+    }
+
+    #[test]
+    fn sink_kafka_exactly_once_config_test() {
+        let mut sink = stand_in_sink(None, None);
+        sink.exactly_once = Some(ExactlyOnce {
+            group_id: "cheeses".into(),
+        });
+        assert_eq!(
+            sink.producer().err().unwrap().to_string(),
+            "exactly_once needs a transactional.id"
+        );
+    }
+
+    #[test]
+    fn source_kafka_exactly_once_test() {
+        let mut consumer = StandInConsumer::new(vec![
+            Event::Record(0, 0, b"brie"),
+            Event::Record(0, 1, b"feta"),
+            Event::Revoke,
+            Event::Record(0, 2, b"edam"),
+        ]);
+        let (f, received) = pipeline();
+
+        let mut source = stand_in_source(1, false);
+        source.exactly_once = true;
+        source.consume(&mut consumer, f, None).unwrap();
+
+        // The output commits the offsets in its transactions instead.
+        assert!(consumer.commits().is_empty());
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
 }
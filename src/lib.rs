@@ -1,18 +1,43 @@
+mod backoff;
+mod interpolate;
+mod json;
+mod json_path;
+mod mapping;
 mod processors;
 mod sinks;
+mod socket;
 mod sources;
+mod syslog;
+
+#[cfg(feature = "http_server")]
+mod admin;
+
+#[cfg(feature = "http_server")]
+mod auth;
+
+#[cfg(feature = "http_server")]
+mod tls;
+
+#[cfg(feature = "json_schema")]
+mod json_schema;
 
 #[cfg(feature = "kafka")]
 mod kafka;
 
+#[cfg(feature = "proto")]
+mod protobuf;
+
 #[cfg(feature = "regexp")]
 mod regex;
 
+#[cfg(feature = "websocket")]
+mod websocket;
+
 use std::{collections::HashMap, fs, path::PathBuf, str};
 
 use failure::Error;
-use futures::future::ok;
-use futures::{Future, Stream};
+use futures::future::{ok, result};
+use futures::{stream, Future, Stream};
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
@@ -20,7 +45,8 @@ pub type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
 
 pub type BoxStream<T, E> = Box<dyn Stream<Item = T, Error = E> + Send>;
 
-pub type BoxFn<T, E> = Box<dyn Fn(T) -> BoxFuture<(), E> + Send>;
+pub type TransactionHandler =
+    Box<dyn Fn(Transaction) -> BoxFuture<Vec<MessageBatch>, Error> + Send + Sync>;
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Transaction {
@@ -41,22 +67,23 @@ pub struct Message {
 
 #[typetag::serde(tag = "type")]
 pub trait Source: Send {
-    fn start(&self, sender: BoxFn<Transaction, Error>) -> Result<(), Error>;
+    fn start(&self, sender: TransactionHandler) -> Result<(), Error>;
 }
 
 pub type ProcessHandler =
-    Box<dyn Fn(BoxStream<MessageBatch, Error>) -> BoxStream<MessageBatch, Error> + Send>;
+    Box<dyn Fn(BoxStream<MessageBatch, Error>) -> BoxStream<MessageBatch, Error> + Send + Sync>;
 
 #[typetag::serde(tag = "type")]
 pub trait Processor: Send {
-    fn create<'a>(&self) -> ProcessHandler;
+    fn create(&self) -> ProcessHandler;
 }
 
-pub type WriteHandler = Box<dyn Fn(BoxStream<MessageBatch, Error>) -> BoxFuture<(), Error> + Send>;
+pub type WriteHandler =
+    Box<dyn Fn(BoxStream<MessageBatch, Error>) -> BoxFuture<(), Error> + Send + Sync>;
 
 #[typetag::serde(tag = "type")]
 pub trait Sink: Send {
-    fn create<'a>(&self) -> WriteHandler;
+    fn create(&self) -> WriteHandler;
 }
 
 #[derive(Deserialize, Serialize)]
@@ -69,9 +96,19 @@ pub struct Spec {
     input: Box<dyn Source>,
     pipeline: Pipeline,
     output: Box<dyn Sink>,
+    #[cfg(feature = "http_server")]
+    #[serde(default)]
+    admin: Option<admin::Admin>,
 }
 
-pub fn start_stream_processor(spec: Spec) {
+pub fn start_stream_processor(spec: Spec) -> Result<(), Error> {
+    #[cfg(feature = "http_server")]
+    {
+        if let Some(admin) = &spec.admin {
+            admin.start()?;
+        }
+    }
+
     let processors = spec
         .pipeline
         .processors
@@ -87,10 +124,13 @@ pub fn start_stream_processor(spec: Spec) {
             for process in processors.iter() {
                 batches = process(batches);
             }
-            output(batches).wait().unwrap();
-            Box::new(ok(()))
+            let processed = batches.collect().wait().and_then(|batches| {
+                output(Box::new(stream::iter_ok(batches.clone())))
+                    .wait()
+                    .map(|_| batches)
+            });
+            Box::new(result(processed))
         }))
-        .unwrap();
 }
 
 #[derive(Debug, StructOpt)]
@@ -116,9 +156,7 @@ pub fn run() -> Result<(), Error> {
 
     let spec: Spec = serde_yaml::from_reader(file)?;
 
-    start_stream_processor(spec);
-
-    Ok(())
+    start_stream_processor(spec)
 }
 
 #[cfg(test)]
@@ -134,18 +172,39 @@ pub mod tests {
             $source
             .start(Box::new(move |batch| {
                 tx.send(batch).unwrap();
-                Box::new(ok(()))
+                Box::new(ok(Vec::new()))
             }))
             .unwrap();
 
             rx.iter().map(|i| i.batch.clone()).collect::<Vec<_>>()
         }};
+        // Stops the source by failing the transaction of its `count`th batch.
+        ( $source:expr, $count:expr ) => {{
+            use std::sync::atomic::{AtomicUsize, Ordering};
+
+            let (tx, rx) = channel();
+            let received = AtomicUsize::new(0);
+            let stopped = $source.start(Box::new(move |batch| {
+                tx.send(batch).unwrap();
+                if received.fetch_add(1, Ordering::SeqCst) + 1 >= $count {
+                    Box::new(futures::future::err(failure::format_err!("consumed")))
+                } else {
+                    Box::new(ok(Vec::new()))
+                }
+            }));
+            assert_eq!(stopped.unwrap_err().to_string(), "consumed");
+
+            rx.try_iter()
+                .take($count)
+                .map(|i| i.batch.clone())
+                .collect::<Vec<_>>()
+        }};
     }
 
     #[macro_export]
     macro_rules! run_processor {
         ( $process:expr, $input:expr ) => {{
-            use crate::tests::block_on;
+            use $crate::tests::block_on;
 
             use failure::format_err;
             use futures::stream;
@@ -185,7 +244,7 @@ pub mod tests {
     #[macro_export]
     macro_rules! no_metdata_batches {
         ( $( $messages:expr ),* ) => {{
-            use crate::MessageBatch;
+            use $crate::MessageBatch;
 
             vec![
                 $(
@@ -0,0 +1,1071 @@
+use std::{collections::HashMap, fmt, str};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc,
+};
+use failure::{format_err, Error};
+use futures::stream::Stream;
+use log::warn;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Number, Value};
+#[cfg(feature = "random")]
+use uuid::Uuid;
+
+use crate::{
+    json_path::{self, kind, lookup},
+    Message, ProcessHandler, Processor,
+};
+
+/// Reshapes messages with a mapping evaluated over their JSON content and metadata. A message
+/// the mapping fails on is left untouched with a `mapping_error`.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct MappingProcessor {
+    mapping: Mapping,
+}
+
+#[typetag::serde(name = "mapping")]
+impl Processor for MappingProcessor {
+    fn create(&self) -> ProcessHandler {
+        let mapping = self.mapping.clone();
+
+        Box::new(move |batches| {
+            let mapping = mapping.clone();
+            let result = batches.map(move |mut b| {
+                b.messages = b
+                    .messages
+                    .into_iter()
+                    .filter_map(|mut message| match mapping.apply(&message) {
+                        Ok(Some(mapped)) => Some(mapped),
+                        Ok(None) => None,
+                        Err(e) => {
+                            warn!("Failed to map message: {}", e);
+                            message
+                                .metadata
+                                .insert("mapping_error".into(), e.to_string());
+                            Some(message)
+                        }
+                    })
+                    .collect();
+                b
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+/// A parsed mapping, made up of statements like:
+///
+/// ```text
+/// root.user = this.user.name.uppercase()
+/// meta source = "api"
+/// if this.age < 18 { root = deleted() } else { root.adult = true }
+/// ```
+///
+/// `this` is the message's JSON content and `root` the document being built. The content is
+/// left as is when nothing is assigned to `root`, and the message is dropped when `root` is
+/// assigned `deleted()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    source: String,
+    statements: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Statement {
+    Assign(Target, Expr),
+    If(Vec<(Expr, Vec<Statement>)>, Vec<Statement>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Root(Vec<String>),
+    Meta(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Literal(Value),
+    This,
+    Root,
+    Array(Vec<Expr>),
+    Object(Vec<(String, Expr)>),
+    Field(Box<Expr>, String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+    Function(String, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl Mapping {
+    pub fn parse(source: &str) -> Result<Mapping, Error> {
+        let mut parser = Parser {
+            tokens: lex(source)?,
+            position: 0,
+        };
+        let statements = parser.statements()?;
+        match parser.peek() {
+            Token::Eof => Ok(Mapping {
+                source: source.to_owned(),
+                statements,
+            }),
+            token => Err(format_err!("unexpected {}", token)),
+        }
+    }
+
+    /// The mapped message, or `None` if it was deleted.
+    pub fn apply(&self, message: &Message) -> Result<Option<Message>, Error> {
+        let mut state = State {
+            input: message,
+            this: None,
+            root: Value::Null,
+            assigned: false,
+            deleted: false,
+            metadata: message.metadata.clone(),
+        };
+        state.run(&self.statements)?;
+
+        if state.deleted {
+            return Ok(None);
+        }
+        let data = match state.root {
+            _ if !state.assigned => message.data.clone(),
+            Value::String(s) => s.into_bytes(),
+            root => serde_json::to_vec(&root)?,
+        };
+        Ok(Some(Message {
+            data,
+            metadata: state.metadata,
+        }))
+    }
+}
+
+struct State<'a> {
+    input: &'a Message,
+    this: Option<Value>,
+    root: Value,
+    assigned: bool,
+    deleted: bool,
+    metadata: HashMap<String, String>,
+}
+
+impl State<'_> {
+    fn run(&mut self, statements: &[Statement]) -> Result<(), Error> {
+        for statement in statements {
+            if self.deleted {
+                break;
+            }
+            match statement {
+                Statement::Assign(target, Expr::Function(name, _)) if name == "deleted" => {
+                    match target {
+                        Target::Root(path) if path.is_empty() => self.deleted = true,
+                        Target::Root(path) => {
+                            json_path::remove(&mut self.root, path);
+                            self.assigned = true;
+                        }
+                        Target::Meta(key) => {
+                            self.metadata.remove(key);
+                        }
+                    }
+                }
+                Statement::Assign(target, expr) => {
+                    let value = self.eval(expr)?;
+                    match target {
+                        Target::Root(path) => {
+                            json_path::set(&mut self.root, path, value)?;
+                            self.assigned = true;
+                        }
+                        Target::Meta(key) => {
+                            self.metadata.insert(key.clone(), to_string(&value));
+                        }
+                    }
+                }
+                Statement::If(branches, otherwise) => {
+                    let mut taken = None;
+                    for (condition, body) in branches {
+                        if self.condition(condition)? {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    self.run(taken.unwrap_or(otherwise))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self, expr: &Expr) -> Result<bool, Error> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            value => Err(format_err!("expected a boolean, got {}", kind(&value))),
+        }
+    }
+
+    fn this(&mut self) -> Result<&Value, Error> {
+        if self.this.is_none() {
+            let this = serde_json::from_slice(&self.input.data)
+                .map_err(|e| format_err!("message is not valid JSON: {}", e))?;
+            self.this = Some(this);
+        }
+        Ok(self.this.as_ref().unwrap())
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, Error> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::This => self.this().cloned(),
+            Expr::Root => Ok(self.root.clone()),
+            Expr::Array(items) => items
+                .iter()
+                .map(|item| self.eval(item))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            Expr::Object(fields) => {
+                let mut object = Map::new();
+                for (key, value) in fields {
+                    object.insert(key.clone(), self.eval(value)?);
+                }
+                Ok(Value::Object(object))
+            }
+            Expr::Field(target, field) => {
+                let value = self.eval(target)?;
+                Ok(lookup(&value, field).cloned().unwrap_or(Value::Null))
+            }
+            Expr::Not(operand) => Ok(Value::Bool(!self.condition(operand)?)),
+            Expr::Neg(operand) => {
+                let value = self.eval(operand)?;
+                arithmetic(&Value::from(0), Op::Sub, &value)
+            }
+            Expr::Binary(left, Op::Or, right) => {
+                Ok(Value::Bool(self.condition(left)? || self.condition(right)?))
+            }
+            Expr::Binary(left, Op::And, right) => {
+                Ok(Value::Bool(self.condition(left)? && self.condition(right)?))
+            }
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (self.eval(left)?, self.eval(right)?);
+                binary(&left, *op, &right)
+            }
+            Expr::Function(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.function(name, &args)
+            }
+            Expr::Method(target, name, args) => {
+                let target = self.eval(target)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                method(&target, name, &args)
+            }
+        }
+    }
+
+    fn function(&self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        match (name, args) {
+            ("meta", [Value::String(key)]) => Ok(self
+                .input
+                .metadata
+                .get(key)
+                .map_or(Value::Null, |v| Value::String(v.clone()))),
+            ("content", []) => Ok(Value::String(
+                String::from_utf8_lossy(&self.input.data).into_owned(),
+            )),
+            ("now", []) => Ok(Value::String(
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            )),
+            ("timestamp_unix", []) => Ok(Value::from(Utc::now().timestamp())),
+            #[cfg(feature = "random")]
+            ("uuid_v4", []) => Ok(Value::String(Uuid::new_v4().to_string())),
+            #[cfg(not(feature = "random"))]
+            ("uuid_v4", []) => Err(format_err!("uuid_v4() needs the random feature")),
+            ("deleted", _) => Err(format_err!("deleted() can only be assigned")),
+            _ => Err(format_err!(
+                "unknown function {}() with {} arguments",
+                name,
+                args.len()
+            )),
+        }
+    }
+}
+
+fn method(target: &Value, name: &str, args: &[Value]) -> Result<Value, Error> {
+    let value = match (name, target, args) {
+        ("or", Value::Null, [default]) => default.clone(),
+        ("or", value, [_]) => value.clone(),
+        ("string", value, []) => Value::String(to_string(value)),
+        ("number", Value::Number(n), []) => Value::Number(n.clone()),
+        ("number", Value::String(s), []) => {
+            let n = s.trim();
+            match n.parse::<i64>() {
+                Ok(i) => Value::from(i),
+                Err(_) => float(
+                    n.parse::<f64>()
+                        .map_err(|_| format_err!("{:?} is not a number", s))?,
+                )?,
+            }
+        }
+        ("length", Value::String(s), []) => Value::from(s.chars().count()),
+        ("length", Value::Array(a), []) => Value::from(a.len()),
+        ("length", Value::Object(o), []) => Value::from(o.len()),
+        ("contains", Value::String(s), [Value::String(x)]) => Value::Bool(s.contains(&**x)),
+        ("contains", Value::Array(a), [x]) => Value::Bool(a.iter().any(|v| equal(v, x))),
+        ("keys", Value::Object(o), []) => o.keys().cloned().map(Value::String).collect(),
+        ("uppercase", Value::String(s), []) => Value::String(s.to_uppercase()),
+        ("lowercase", Value::String(s), []) => Value::String(s.to_lowercase()),
+        ("trim", Value::String(s), []) => Value::String(s.trim().to_owned()),
+        ("has_prefix", Value::String(s), [Value::String(x)]) => Value::Bool(s.starts_with(&**x)),
+        ("has_suffix", Value::String(s), [Value::String(x)]) => Value::Bool(s.ends_with(&**x)),
+        ("replace", Value::String(s), [Value::String(from), Value::String(to)]) => {
+            Value::String(s.replace(&**from, to))
+        }
+        ("split", Value::String(s), [Value::String(sep)]) => s
+            .split(&**sep)
+            .map(|part| Value::String(part.to_owned()))
+            .collect(),
+        ("join", Value::Array(a), [Value::String(sep)]) => {
+            Value::String(a.iter().map(to_string).collect::<Vec<_>>().join(sep))
+        }
+        ("round", Value::Number(n), []) => rounded(n, f64::round)?,
+        ("floor", Value::Number(n), []) => rounded(n, f64::floor)?,
+        ("ceil", Value::Number(n), []) => rounded(n, f64::ceil)?,
+        ("abs", Value::Number(n), []) => match n.as_i64() {
+            Some(i) => Value::from(i.abs()),
+            None => float(number(n).abs())?,
+        },
+        ("parse_timestamp", Value::String(s), []) => {
+            Value::from(DateTime::parse_from_rfc3339(s)?.timestamp())
+        }
+        ("parse_timestamp", Value::String(s), [Value::String(format)]) => {
+            Value::from(parse_timestamp(s, format)?)
+        }
+        ("format_timestamp", Value::Number(n), []) => {
+            Value::String(timestamp(n)?.to_rfc3339_opts(SecondsFormat::Secs, true))
+        }
+        ("format_timestamp", Value::Number(n), [Value::String(format)]) => {
+            // Formatting with an invalid specifier panics once it's written out.
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format_err!("invalid timestamp format {:?}", format));
+            }
+            Value::String(timestamp(n)?.format(format).to_string())
+        }
+        _ => {
+            return Err(format_err!(
+                "{}() with {} arguments can't be used on {}",
+                name,
+                args.len(),
+                kind(target)
+            ))
+        }
+    };
+    Ok(value)
+}
+
+/// Seconds since the epoch of a timestamp with or without a zone, or of a date.
+fn parse_timestamp(s: &str, format: &str) -> Result<i64, Error> {
+    if let Ok(t) = DateTime::parse_from_str(s, format) {
+        return Ok(t.timestamp());
+    }
+    if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+        return Ok(t.timestamp());
+    }
+    NaiveDate::parse_from_str(s, format)
+        .map(|d| d.and_hms(0, 0, 0).timestamp())
+        .map_err(|e| format_err!("can't parse {:?} as {:?}: {}", s, format, e))
+}
+
+fn timestamp(n: &Number) -> Result<DateTime<Utc>, Error> {
+    Utc.timestamp_opt(number(n) as i64, 0)
+        .single()
+        .ok_or_else(|| format_err!("{} is out of range for a timestamp", n))
+}
+
+fn rounded(n: &Number, f: fn(f64) -> f64) -> Result<Value, Error> {
+    if n.is_f64() {
+        let rounded = f(number(n));
+        if rounded.abs() < i64::MAX as f64 {
+            return Ok(Value::from(rounded as i64));
+        }
+        return float(rounded);
+    }
+    Ok(Value::Number(n.clone()))
+}
+
+fn binary(left: &Value, op: Op, right: &Value) -> Result<Value, Error> {
+    let value = match op {
+        Op::Eq => Value::Bool(equal(left, right)),
+        Op::Ne => Value::Bool(!equal(left, right)),
+        Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+            let ordering = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => number(l).partial_cmp(&number(r)),
+                (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+                _ => None,
+            }
+            .ok_or_else(|| format_err!("can't compare {} with {}", kind(left), kind(right)))?;
+            Value::Bool(match op {
+                Op::Lt => ordering.is_lt(),
+                Op::Le => ordering.is_le(),
+                Op::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            })
+        }
+        Op::Add => match (left, right) {
+            (Value::String(l), Value::String(r)) => Value::String(format!("{}{}", l, r)),
+            _ => arithmetic(left, op, right)?,
+        },
+        _ => arithmetic(left, op, right)?,
+    };
+    Ok(value)
+}
+
+fn arithmetic(left: &Value, op: Op, right: &Value) -> Result<Value, Error> {
+    let (l, r) = match (left, right) {
+        (Value::Number(l), Value::Number(r)) => (l, r),
+        _ => {
+            return Err(format_err!(
+                "can't apply {} to {} and {}",
+                op,
+                kind(left),
+                kind(right)
+            ))
+        }
+    };
+
+    if let (Some(l), Some(r), false) = (l.as_i64(), r.as_i64(), op == Op::Div) {
+        let result = match op {
+            Op::Add => l.checked_add(r),
+            Op::Sub => l.checked_sub(r),
+            Op::Mul => l.checked_mul(r),
+            _ => l.checked_rem(r),
+        };
+        if let Some(result) = result {
+            return Ok(Value::from(result));
+        }
+    }
+
+    let (l, r) = (number(l), number(r));
+    float(match op {
+        Op::Add => l + r,
+        Op::Sub => l - r,
+        Op::Mul => l * r,
+        Op::Div => l / r,
+        _ => l % r,
+    })
+}
+
+fn number(n: &Number) -> f64 {
+    n.as_f64().unwrap_or_default()
+}
+
+fn float(f: f64) -> Result<Value, Error> {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .ok_or_else(|| format_err!("{} is not a valid number", f))
+}
+
+/// Numbers are equal by value, whether they are integers or not.
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => number(l) == number(r),
+        _ => left == right,
+    }
+}
+
+fn to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(Number),
+    Punct(&'static str),
+    Newline,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "{:?}", ident),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Num(n) => write!(f, "number {}", n),
+            Token::Punct(p) => write!(f, "{:?}", p),
+            Token::Newline => f.write_str("end of line"),
+            Token::Eof => f.write_str("end of mapping"),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            Op::Or => "||",
+            Op::And => "&&",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Rem => "%",
+        };
+        f.write_str(op)
+    }
+}
+
+const PUNCTUATION: [&str; 25] = [
+    "==", "!=", "<=", ">=", "&&", "||", ".", ",", "(", ")", "[", "]", "{", "}", ":", "=", "<", ">",
+    "+", "-", "*", "/", "%", "!", ";",
+];
+
+fn lex(source: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            '\n' => {
+                chars.next();
+                tokens.push(Token::Newline);
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                while chars.peek().is_some_and(|&(_, c)| c != '\n') {
+                    chars.next();
+                }
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => s.push('\n'),
+                            Some((_, 't')) => s.push('\t'),
+                            Some((_, 'r')) => s.push('\r'),
+                            Some((_, c @ '"')) | Some((_, c @ '\\')) => s.push(c),
+                            _ => return Err(format_err!("invalid escape in string at {}", i)),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(format_err!("unterminated string at {}", i)),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = i;
+                while let Some(&(j, c)) = chars.peek() {
+                    // A dot only continues the number if a digit follows, so `1.string()` works,
+                    // and never in a path like `this.items.0.name`.
+                    let fraction = c == '.'
+                        && tokens.last() != Some(&Token::Punct("."))
+                        && !source[i..j].contains('.')
+                        && source[j + 1..].starts_with(|c: char| c.is_ascii_digit());
+                    if !c.is_ascii_digit() && !fraction {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                let literal = &source[i..end];
+                let number = match literal.parse::<i64>() {
+                    Ok(n) => Number::from(n),
+                    Err(_) => literal
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .ok_or_else(|| format_err!("invalid number {}", literal))?,
+                };
+                tokens.push(Token::Num(number));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !c.is_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            _ => {
+                let punct = PUNCTUATION
+                    .iter()
+                    .find(|p| source[i..].starts_with(*p))
+                    .ok_or_else(|| format_err!("unexpected {:?} at {}", c, i))?;
+                for _ in 0..punct.len() {
+                    chars.next();
+                }
+                tokens.push(Token::Punct(punct));
+            }
+        }
+    }
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token != Token::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if *self.peek() == Token::Punct(punct) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<(), Error> {
+        if self.eat(punct) {
+            return Ok(());
+        }
+        Err(format_err!("expected {:?}, found {}", punct, self.peek()))
+    }
+
+    fn skip_newlines(&mut self) {
+        while *self.peek() == Token::Newline || *self.peek() == Token::Punct(";") {
+            self.position += 1;
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Token::Ident(ident) | Token::Str(ident) => Ok(ident),
+            Token::Num(ref n) if n.is_u64() => Ok(n.to_string()),
+            token => Err(format_err!("expected a name, found {}", token)),
+        }
+    }
+
+    /// Whether an `else` comes next, skipping past it if so. It can be on the line after a `}`.
+    fn else_follows(&mut self) -> bool {
+        let position = self.position;
+        while *self.peek() == Token::Newline {
+            self.position += 1;
+        }
+        if *self.peek() == Token::Ident("else".into()) {
+            self.position += 1;
+            return true;
+        }
+        self.position = position;
+        false
+    }
+
+    fn statements(&mut self) -> Result<Vec<Statement>, Error> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                Token::Eof | Token::Punct("}") => return Ok(statements),
+                _ => statements.push(self.statement()?),
+            }
+            match self.peek() {
+                Token::Newline | Token::Punct(";") | Token::Punct("}") | Token::Eof => (),
+                token => return Err(format_err!("expected end of line, found {}", token)),
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, Error> {
+        self.expect("{")?;
+        let statements = self.statements()?;
+        self.expect("}")?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Error> {
+        let target = match self.next() {
+            Token::Ident(ref ident) if ident == "if" => {
+                let mut branches = vec![(self.expr()?, self.block()?)];
+                let mut otherwise = Vec::new();
+                while self.else_follows() {
+                    if *self.peek() == Token::Ident("if".into()) {
+                        self.next();
+                        branches.push((self.expr()?, self.block()?));
+                    } else {
+                        otherwise = self.block()?;
+                        break;
+                    }
+                }
+                return Ok(Statement::If(branches, otherwise));
+            }
+            Token::Ident(ref ident) if ident == "meta" => Target::Meta(self.ident()?),
+            Token::Ident(ref ident) if ident == "root" => {
+                let mut path = Vec::new();
+                while self.eat(".") {
+                    path.push(self.ident()?);
+                }
+                Target::Root(path)
+            }
+            token => {
+                return Err(format_err!(
+                    "expected root, meta or if to start a statement, found {}",
+                    token
+                ))
+            }
+        };
+        self.expect("=")?;
+        Ok(Statement::Assign(target, self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        self.binary(0)
+    }
+
+    /// Operators by increasing precedence.
+    const PRECEDENCE: [&'static [(&'static str, Op)]; 5] = [
+        &[("||", Op::Or)],
+        &[("&&", Op::And)],
+        &[
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ],
+        &[("+", Op::Add), ("-", Op::Sub)],
+        &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+    ];
+
+    fn binary(&mut self, level: usize) -> Result<Expr, Error> {
+        if level == Self::PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (punct, op) in Self::PRECEDENCE[level] {
+                if self.eat(punct) {
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(Box::new(left), *op, Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.primary()?;
+        while self.eat(".") {
+            let name = self.ident()?;
+            expr = if *self.peek() == Token::Punct("(") {
+                Expr::Method(Box::new(expr), name, self.args()?)
+            } else {
+                Expr::Field(Box::new(expr), name)
+            };
+        }
+        Ok(expr)
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, Error> {
+        self.expect("(")?;
+        self.list(")")
+    }
+
+    /// Comma separated expressions up to `close`, which may span lines.
+    fn list(&mut self, close: &'static str) -> Result<Vec<Expr>, Error> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.eat(close) {
+                return Ok(items);
+            }
+            items.push(self.expr()?);
+            self.skip_newlines();
+            if !self.eat(",") {
+                self.expect(close)?;
+                return Ok(items);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        match self.next() {
+            Token::Str(s) => Ok(Expr::Literal(Value::String(s))),
+            Token::Num(n) => Ok(Expr::Literal(Value::Number(n))),
+            Token::Ident(ident) => match &*ident {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                "this" => Ok(Expr::This),
+                "root" => Ok(Expr::Root),
+                _ if *self.peek() == Token::Punct("(") => Ok(Expr::Function(ident, self.args()?)),
+                _ => Err(format_err!("unknown name {:?}", ident)),
+            },
+            Token::Punct("(") => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Punct("[") => Ok(Expr::Array(self.list("]")?)),
+            Token::Punct("{") => {
+                let mut fields = Vec::new();
+                loop {
+                    self.skip_newlines();
+                    if self.eat("}") {
+                        return Ok(Expr::Object(fields));
+                    }
+                    let key = self.ident()?;
+                    self.expect(":")?;
+                    fields.push((key, self.expr()?));
+                    self.skip_newlines();
+                    if !self.eat(",") {
+                        self.expect("}")?;
+                        return Ok(Expr::Object(fields));
+                    }
+                }
+            }
+            token => Err(format_err!("expected a value, found {}", token)),
+        }
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Mapping {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Mapping::parse(&source).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::no_metdata_batches;
+
+    fn map(mapping: &str, data: &str) -> Result<Option<Message>, Error> {
+        let mut message = Message {
+            data: data.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        message.metadata.insert("origin".into(), "france".into());
+        Mapping::parse(mapping).unwrap().apply(&message)
+    }
+
+    fn json(mapping: &str, data: &str) -> Value {
+        let message = map(mapping, data).unwrap().unwrap();
+        serde_json::from_slice(&message.data).unwrap()
+    }
+
+    #[test]
+    fn mapping_reshape_test() {
+        let mapping = r#"
+            # Only the fields we care about.
+            root.user = this.user.name.uppercase()
+            root.tags = this.tags.join(",")
+            root.first_tag = this.tags.0
+            root.score = (this.score + 1) * 2 / 4
+            root.summary = {"name": this.user.name, "count": this.tags.length()}
+            root.missing = this.user.age.or(0)
+        "#;
+        assert_eq!(
+            json(
+                mapping,
+                r#"{"user":{"name":"brie"},"tags":["soft","french"],"score":3}"#
+            ),
+            serde_json::json!({
+                "user": "BRIE",
+                "tags": "soft,french",
+                "first_tag": "soft",
+                "score": 2.0,
+                "summary": {"name": "brie", "count": 2},
+                "missing": 0,
+            })
+        );
+    }
+
+    #[test]
+    fn mapping_metadata_test() {
+        let message = map(
+            r#"meta cheese = meta("origin") + "/" + this.name
+            meta origin = deleted()
+            meta count = 3"#,
+            r#"{"name":"brie"}"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        // The content is left alone when root isn't assigned.
+        assert_eq!(message.data, br#"{"name":"brie"}"#);
+        assert_eq!(message.metadata["cheese"], "france/brie");
+        assert_eq!(message.metadata["count"], "3");
+        assert!(!message.metadata.contains_key("origin"));
+    }
+
+    #[test]
+    fn mapping_conditional_test() {
+        let mapping = r#"
+            root = this
+            if this.age < 18 {
+                root = deleted()
+            } else if this.age >= 65 && !this.working { root.group = "retired" }
+            else { root.group = "adult"; root.age = deleted() }
+        "#;
+
+        assert!(map(mapping, r#"{"age":12}"#).unwrap().is_none());
+        assert_eq!(
+            json(mapping, r#"{"age":70,"working":false}"#),
+            serde_json::json!({"age": 70, "working": false, "group": "retired"})
+        );
+        assert_eq!(
+            json(mapping, r#"{"age":30,"working":true}"#),
+            serde_json::json!({"working": true, "group": "adult"})
+        );
+    }
+
+    #[test]
+    fn mapping_functions_test() {
+        let mapping = r#"
+            root.text = content().trim().replace("e", "o").split(" ")
+            root.number = "12.5".number().floor() + "3".number()
+            root.parsed = "2019-11-01 10:00:00".parse_timestamp("%Y-%m-%d %H:%M:%S")
+            root.date = "2019-11-01".parse_timestamp("%Y-%m-%d").format_timestamp("%d/%m/%Y")
+            root.rfc3339 = "2019-11-01T10:00:00+01:00".parse_timestamp().format_timestamp()
+            root.checks = [content().contains("cheese"), "a" != "b", 2 == 2.0, -1 < 0]
+            root.string = 12.string() + true.string()
+        "#;
+        let value = json(mapping, " cheese ");
+
+        assert_eq!(value["text"], serde_json::json!(["chooso"]));
+        assert_eq!(value["number"], 15);
+        assert_eq!(value["parsed"], 1_572_602_400);
+        assert_eq!(value["date"], "01/11/2019");
+        assert_eq!(value["rfc3339"], "2019-11-01T09:00:00Z");
+        assert_eq!(value["checks"], serde_json::json!([true, true, true, true]));
+        assert_eq!(value["string"], "12true");
+        assert_eq!(
+            map("root = now()", "").unwrap().unwrap().data.len(),
+            "2019-11-01T10:00:00.000Z".len()
+        );
+    }
+
+    #[test]
+    fn mapping_errors_test() {
+        assert_eq!(
+            map("root.name = this.name", "cheese")
+                .unwrap_err()
+                .to_string(),
+            "message is not valid JSON: expected value at line 1 column 1"
+        );
+        assert_eq!(
+            map("root = this.name.uppercase()", r#"{"name":1}"#)
+                .unwrap_err()
+                .to_string(),
+            "uppercase() with 0 arguments can't be used on a number"
+        );
+        assert!(map("if this.name { root = 1 }", r#"{"name":"brie"}"#).is_err());
+        assert!(map("root = 1 + \"a\"", "{}").is_err());
+        assert_eq!(
+            map("root = 0.format_timestamp(\"%Y-%Q\")", "{}")
+                .unwrap_err()
+                .to_string(),
+            "invalid timestamp format \"%Y-%Q\""
+        );
+
+        assert!(Mapping::parse("root.name = ").is_err());
+        assert!(Mapping::parse("this.name = 1").is_err());
+        assert!(Mapping::parse("root = bacon").is_err());
+        assert!(Mapping::parse("root = \"brie").is_err());
+        assert!(Mapping::parse("root = 1 root = 2").is_err());
+        assert!(serde_yaml::from_str::<Mapping>("root = (1").is_err());
+    }
+
+    #[test]
+    fn process_mapping_batch_test() {
+        let processor: MappingProcessor = serde_yaml::from_str(
+            r#"
+            mapping: |
+              root.cheese = this.name.uppercase()
+              if this.name == "bacon" { root = deleted() }
+            "#,
+        )
+        .unwrap();
+
+        let messages = vec![r#"{"name":"brie"}"#, r#"{"name":"bacon"}"#, "feta"]
+            .into_iter()
+            .map(|data| Message {
+                data: data.as_bytes().to_vec(),
+                ..Default::default()
+            })
+            .collect();
+        let batches = crate::run_processor!(processor, no_metdata_batches![messages]);
+
+        let messages = &batches[0].messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].data, br#"{"cheese":"BRIE"}"#);
+        assert_eq!(messages[1].data, b"feta");
+        assert!(messages[1].metadata.contains_key("mapping_error"));
+    }
+}
@@ -4,16 +4,15 @@ use std::str;
 
 use futures::stream::Stream;
 use serde::{Deserialize, Serialize};
-use typetag::serde;
 
-use crate::{Message, ProcessHandler, Processor};
+use crate::{interpolate::Template, Message, ProcessHandler, Processor};
 
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 struct Noop;
 
 #[typetag::serde(name = "noop")]
 impl Processor for Noop {
-    fn create<'a>(&self) -> ProcessHandler {
+    fn create(&self) -> ProcessHandler {
         Box::new(|batches| batches)
     }
 }
@@ -21,21 +20,23 @@ impl Processor for Noop {
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 struct Replace {
     from: String,
-    to: String,
+    to: Template,
 }
 
 #[typetag::serde(name = "replace")]
 impl Processor for Replace {
-    fn create<'a>(&self) -> ProcessHandler {
-        let (from, to) = (self.from.to_owned(), self.to.to_owned());
+    fn create(&self) -> ProcessHandler {
+        let (from, to) = (self.from.to_owned(), self.to.clone());
 
         Box::new(move |batches| {
-            let (from, to) = (from.to_owned(), to.to_owned());
+            let (from, to) = (from.to_owned(), to.clone());
             let result = batches.map(move |mut b| {
+                let batch_size = b.messages.len();
                 b.messages = b
                     .messages
                     .into_iter()
                     .map(|mut message| {
+                        let to = to.render_in_batch(&message, batch_size);
                         let source = str::from_utf8(&message.data).unwrap().to_owned();
                         message.data = source.replace(&from, &to).into();
                         message
@@ -60,7 +61,7 @@ mod replace_tests {
             $crate::run_processor!(
                 Replace {
                     from: $from.into(),
-                    to: $to.into(),
+                    to: Template::parse($to).unwrap(),
                 },
                 $input
             )
@@ -96,22 +97,44 @@ mod replace_tests {
             ]
         );
     }
+
+    #[test]
+    fn process_replace_interpolated_test() {
+        let mut message = Message {
+            data: b"cheese|geese".to_vec(),
+            ..Default::default()
+        };
+        message.metadata.insert("vowels".into(), "oo".into());
+
+        let batches = replace!(
+            "ee",
+            "${! metadata:vowels }${! batch_size }",
+            no_metdata_batches![vec![message, Message::default()]]
+        );
+        assert_eq!(batches[0].messages[0].data, b"choo2se|goo2se");
+    }
 }
 
 #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
 struct Process {
     name: String,
-    args: Vec<String>,
+    /// Rendered once per batch, against its first message.
+    args: Vec<Template>,
 }
 
 #[typetag::serde(name = "process")]
 impl Processor for Process {
-    fn create<'a>(&self) -> ProcessHandler {
+    fn create(&self) -> ProcessHandler {
         let (name, args) = (self.name.to_owned(), self.args.to_owned());
 
         Box::new(move |batches| {
             let (name, args) = (name.to_owned(), args.to_owned());
             let result = batches.map(move |mut b| {
+                let first = b.messages.first().cloned().unwrap_or_default();
+                let args = args
+                    .iter()
+                    .map(|arg| arg.render_in_batch(&first, b.messages.len()))
+                    .collect::<Vec<_>>();
                 let mut child_process = Command::new(&name)
                     .args(&args)
                     .stdin(Stdio::piped())
@@ -125,9 +148,9 @@ impl Processor for Process {
                         .into_iter()
                         .map(|m| m.data)
                         .collect::<Vec<_>>()
-                        .join(&('\n' as u8));
+                        .join(&b'\n');
 
-                    data.push('\n' as u8);
+                    data.push(b'\n');
                     stdin.write_all(&data).expect("failed to write to stdin");
                 }
                 let output = child_process
@@ -136,7 +159,7 @@ impl Processor for Process {
                 let data = output.stdout;
 
                 b.messages = data
-                    .split(|i| i == &('\n' as u8))
+                    .split(|i| i == &b'\n')
                     .filter(|s| !s.is_empty())
                     .map(|d| Message {
                         data: d.to_vec(),
@@ -162,7 +185,10 @@ mod process_tests {
             $crate::run_processor!(
                 Process {
                     name: $name.into(),
-                    args: $args.into_iter().map(|s| s.into()).collect(),
+                    args: $args
+                        .into_iter()
+                        .map(|s| Template::parse(s).unwrap())
+                        .collect(),
                 },
                 $input
             )
@@ -198,4 +224,20 @@ mod process_tests {
             ]
         );
     }
+
+    #[test]
+    fn process_interpolated_args_test() {
+        let mut message = Message {
+            data: b"brie".to_vec(),
+            ..Default::default()
+        };
+        message.metadata.insert("kind".into(), "cheese".into());
+
+        let batches = process!(
+            "awk",
+            vec!["-v", "kind=${! metadata:kind }", "{print kind \":\" $0}"],
+            no_metdata_batches![vec![message]]
+        );
+        assert_eq!(batches[0].messages[0].data, b"cheese:brie");
+    }
 }
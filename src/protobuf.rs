@@ -0,0 +1,856 @@
+use std::{collections::HashMap, convert::TryFrom, fs, path::PathBuf, sync::Arc};
+
+use failure::{format_err, Error};
+use futures::stream::Stream;
+use log::warn;
+use protobuf::{
+    descriptor::{
+        DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label,
+        FieldDescriptorProto_Type, FileDescriptorSet,
+    },
+    wire_format::WireType,
+    CodedInputStream, CodedOutputStream,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::{ProcessHandler, Processor};
+
+/// Converts messages between binary protobuf and JSON as the type `message` from the
+/// `FileDescriptorSet` at `descriptor_set`, such as one written by `protoc --include_imports
+/// --descriptor_set_out`. The JSON follows protobuf's own mapping, so 64 bit integers are
+/// strings, bytes are base64 and enums are their names. Messages that can't be converted are
+/// left untouched with a `protobuf_error`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "ProtobufConfig", into = "ProtobufConfig")]
+struct Protobuf {
+    config: ProtobufConfig,
+    types: Arc<Types>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct ProtobufConfig {
+    operator: Operator,
+    descriptor_set: PathBuf,
+    message: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Operator {
+    ToJson,
+    FromJson,
+}
+
+#[typetag::serde(name = "protobuf")]
+impl Processor for Protobuf {
+    fn create(&self) -> ProcessHandler {
+        let (operator, message) = (self.config.operator, self.config.message.clone());
+        let types = self.types.clone();
+
+        Box::new(move |batches| {
+            let (message, types) = (message.clone(), types.clone());
+            let result = batches.map(move |mut b| {
+                for m in &mut b.messages {
+                    let converted = match operator {
+                        Operator::ToJson => types
+                            .decode(&message, &m.data)
+                            .and_then(|json| Ok(serde_json::to_vec(&json)?)),
+                        Operator::FromJson => serde_json::from_slice(&m.data)
+                            .map_err(|e| format_err!("message is not valid JSON: {}", e))
+                            .and_then(|json| types.encode(&message, &json)),
+                    };
+                    match converted {
+                        Ok(data) => m.data = data,
+                        Err(e) => {
+                            warn!("Failed to convert protobuf message: {}", e);
+                            m.metadata.insert("protobuf_error".into(), e.to_string());
+                        }
+                    }
+                }
+                b
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+impl TryFrom<ProtobufConfig> for Protobuf {
+    type Error = Error;
+
+    fn try_from(config: ProtobufConfig) -> Result<Protobuf, Error> {
+        let descriptors = fs::read(&config.descriptor_set)
+            .map_err(|e| format_err!("can't read {:?}: {}", config.descriptor_set, e))?;
+        let types = Types::parse(&descriptors)?;
+        types.message(&config.message)?;
+        Ok(Protobuf {
+            config,
+            types: Arc::new(types),
+        })
+    }
+}
+
+impl From<Protobuf> for ProtobufConfig {
+    fn from(protobuf: Protobuf) -> ProtobufConfig {
+        protobuf.config
+    }
+}
+
+/// How deep messages can be nested when decoding, as in the reference implementations.
+const MAX_DEPTH: usize = 100;
+
+/// The message and enum types of a descriptor set by their full names.
+#[derive(Debug, Default, PartialEq)]
+struct Types {
+    messages: HashMap<String, MessageType>,
+    enums: HashMap<String, Vec<(String, i32)>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct MessageType {
+    fields: Vec<FieldDescriptorProto>,
+    proto3: bool,
+    map_entry: bool,
+}
+
+impl Types {
+    fn parse(descriptors: &[u8]) -> Result<Types, Error> {
+        let set: FileDescriptorSet = protobuf::parse_from_bytes(descriptors)
+            .map_err(|e| format_err!("invalid descriptor set: {}", e))?;
+        let mut types = Types::default();
+        for file in set.get_file() {
+            let proto3 = file.get_syntax() == "proto3";
+            let prefix = match file.get_package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            types.add_enums(&prefix, file.get_enum_type());
+            for message in file.get_message_type() {
+                types.add_message(&prefix, message, proto3);
+            }
+        }
+        Ok(types)
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto, proto3: bool) {
+        let name = format!("{}{}", prefix, message.get_name());
+        let prefix = format!("{}.", name);
+        self.add_enums(&prefix, message.get_enum_type());
+        for nested in message.get_nested_type() {
+            self.add_message(&prefix, nested, proto3);
+        }
+        self.messages.insert(
+            name,
+            MessageType {
+                fields: message.get_field().to_vec(),
+                proto3,
+                map_entry: message.get_options().get_map_entry(),
+            },
+        );
+    }
+
+    fn add_enums(&mut self, prefix: &str, enums: &[EnumDescriptorProto]) {
+        for e in enums {
+            let values = e
+                .get_value()
+                .iter()
+                .map(|v| (v.get_name().to_owned(), v.get_number()))
+                .collect();
+            self.enums
+                .insert(format!("{}{}", prefix, e.get_name()), values);
+        }
+    }
+
+    /// Looks up a type by its full name, with or without the leading dot used in descriptors.
+    fn message(&self, name: &str) -> Result<&MessageType, Error> {
+        self.messages
+            .get(name.trim_start_matches('.'))
+            .ok_or_else(|| format_err!("unknown message type {:?}", name))
+    }
+
+    fn enum_values(&self, name: &str) -> Result<&[(String, i32)], Error> {
+        self.enums
+            .get(name.trim_start_matches('.'))
+            .map(Vec::as_slice)
+            .ok_or_else(|| format_err!("unknown enum type {:?}", name))
+    }
+
+    fn decode(&self, name: &str, data: &[u8]) -> Result<Value, Error> {
+        self.decode_nested(name, data, 0)
+    }
+
+    /// Decodes a message nested `depth` messages deep, so recursive types can't overflow the
+    /// stack.
+    fn decode_nested(&self, name: &str, data: &[u8], depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            return Err(format_err!("message nesting is deeper than {}", MAX_DEPTH));
+        }
+        let message = self.message(name)?;
+        let mut input = CodedInputStream::from_bytes(data);
+        let mut object = Map::new();
+        while !input.eof()? {
+            let (number, wire_type) = input.read_tag_unpack()?;
+            let field = match message.field(number) {
+                Some(field) => field,
+                None => {
+                    input.skip_field(wire_type)?;
+                    continue;
+                }
+            };
+
+            let key = json_name(field);
+            if !is_repeated(field) {
+                check_wire_type(field, wire_type)?;
+                let value = self.read(&mut input, field, depth)?;
+                object.insert(key, value);
+                continue;
+            }
+
+            let mut values = Vec::new();
+            if wire_type == WireType::WireTypeLengthDelimited && is_packable(field) {
+                let length = input.read_raw_varint64()?;
+                let limit = input.push_limit(length)?;
+                while !input.eof()? {
+                    values.push(self.read(&mut input, field, depth)?);
+                }
+                input.pop_limit(limit);
+            } else {
+                check_wire_type(field, wire_type)?;
+                values.push(self.read(&mut input, field, depth)?);
+            }
+
+            match self.map_entry(field)? {
+                Some(_) => {
+                    let entries = object
+                        .entry(key)
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    for mut entry in values {
+                        let key = match entry.get("key") {
+                            Some(Value::String(key)) => key.clone(),
+                            Some(key) => key.to_string(),
+                            None => String::new(),
+                        };
+                        let value = entry.get_mut("value").map_or(Value::Null, Value::take);
+                        if let Value::Object(entries) = entries {
+                            entries.insert(key, value);
+                        }
+                    }
+                }
+                None => {
+                    let array = object
+                        .entry(key)
+                        .or_insert_with(|| Value::Array(Vec::new()));
+                    if let Value::Array(array) = array {
+                        array.extend(values);
+                    }
+                }
+            }
+        }
+        Ok(Value::Object(object))
+    }
+
+    fn read(
+        &self,
+        input: &mut CodedInputStream,
+        field: &FieldDescriptorProto,
+        depth: usize,
+    ) -> Result<Value, Error> {
+        use FieldDescriptorProto_Type::*;
+
+        let value = match field.get_field_type() {
+            TYPE_DOUBLE => float(input.read_double()?),
+            TYPE_FLOAT => float(f64::from(input.read_float()?)),
+            TYPE_INT64 => input.read_int64()?.to_string().into(),
+            TYPE_SINT64 => input.read_sint64()?.to_string().into(),
+            TYPE_SFIXED64 => input.read_sfixed64()?.to_string().into(),
+            TYPE_UINT64 => input.read_uint64()?.to_string().into(),
+            TYPE_FIXED64 => input.read_fixed64()?.to_string().into(),
+            TYPE_INT32 => input.read_int32()?.into(),
+            TYPE_SINT32 => input.read_sint32()?.into(),
+            TYPE_SFIXED32 => input.read_sfixed32()?.into(),
+            TYPE_UINT32 => input.read_uint32()?.into(),
+            TYPE_FIXED32 => input.read_fixed32()?.into(),
+            TYPE_BOOL => input.read_bool()?.into(),
+            TYPE_STRING => input.read_string()?.into(),
+            TYPE_BYTES => base64::encode(&input.read_bytes()?).into(),
+            TYPE_ENUM => {
+                let number = input.read_int32()?;
+                match self
+                    .enum_values(field.get_type_name())?
+                    .iter()
+                    .find(|(_, n)| *n == number)
+                {
+                    Some((name, _)) => name.clone().into(),
+                    None => number.into(),
+                }
+            }
+            TYPE_MESSAGE => {
+                self.decode_nested(field.get_type_name(), &input.read_bytes()?, depth + 1)?
+            }
+            TYPE_GROUP => return Err(format_err!("groups aren't supported")),
+        };
+        Ok(value)
+    }
+
+    fn encode(&self, name: &str, json: &Value) -> Result<Vec<u8>, Error> {
+        let message = self.message(name)?;
+        let object = match json {
+            Value::Object(object) => object,
+            other => {
+                return Err(format_err!(
+                    "expected an object for {}, got {}",
+                    name,
+                    other
+                ))
+            }
+        };
+
+        if let Some(key) = object.keys().find(|key| message.named(key).is_none()) {
+            return Err(format_err!("{} has no field {:?}", name, key));
+        }
+
+        let mut data = Vec::new();
+        {
+            let mut output = CodedOutputStream::vec(&mut data);
+            // Fields are written in the order they're declared, like protobuf libraries do.
+            for field in &message.fields {
+                let key = json_name(field);
+                let value = match object.get(&key).or_else(|| object.get(field.get_name())) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let number = field.get_number() as u32;
+
+                match (value, self.map_entry(field)?) {
+                    (Value::Null, _) => (),
+                    (Value::Object(entries), Some(entry)) => {
+                        for (key, value) in entries {
+                            let mut pair = Map::new();
+                            pair.insert("key".into(), map_key(entry, key)?);
+                            pair.insert("value".into(), value.clone());
+                            let bytes = self.encode(field.get_type_name(), &pair.into())?;
+                            output.write_bytes(number, &bytes)?;
+                        }
+                    }
+                    (Value::Array(values), None) if is_repeated(field) => {
+                        if is_packed(message, field) {
+                            let mut packed = Vec::new();
+                            {
+                                let mut packed_output = CodedOutputStream::vec(&mut packed);
+                                for value in values {
+                                    self.write(&mut packed_output, field, value)?;
+                                }
+                                packed_output.flush()?;
+                            }
+                            output.write_bytes(number, &packed)?;
+                        } else {
+                            for value in values {
+                                output.write_tag(number, wire_type(field))?;
+                                self.write(&mut output, field, value)?;
+                            }
+                        }
+                    }
+                    (value, _) if !is_repeated(field) => {
+                        output.write_tag(number, wire_type(field))?;
+                        self.write(&mut output, field, value)?;
+                    }
+                    (value, _) => {
+                        return Err(format_err!(
+                            "expected {:?} to be repeated, got {}",
+                            key,
+                            value
+                        ))
+                    }
+                }
+            }
+            output.flush()?;
+        }
+        Ok(data)
+    }
+
+    /// Writes a value without its tag.
+    fn write(
+        &self,
+        output: &mut CodedOutputStream,
+        field: &FieldDescriptorProto,
+        value: &Value,
+    ) -> Result<(), Error> {
+        use FieldDescriptorProto_Type::*;
+
+        let invalid = || format_err!("invalid value {} for {:?}", value, field.get_name());
+        match field.get_field_type() {
+            TYPE_DOUBLE => output.write_double_no_tag(to_float(value).ok_or_else(invalid)?)?,
+            TYPE_FLOAT => output.write_float_no_tag(to_float(value).ok_or_else(invalid)? as f32)?,
+            TYPE_INT64 => output.write_int64_no_tag(to_int(value).ok_or_else(invalid)?)?,
+            TYPE_SINT64 => output.write_sint64_no_tag(to_int(value).ok_or_else(invalid)?)?,
+            TYPE_SFIXED64 => output.write_sfixed64_no_tag(to_int(value).ok_or_else(invalid)?)?,
+            TYPE_UINT64 => output.write_uint64_no_tag(to_uint(value).ok_or_else(invalid)?)?,
+            TYPE_FIXED64 => output.write_fixed64_no_tag(to_uint(value).ok_or_else(invalid)?)?,
+            TYPE_INT32 => output.write_int32_no_tag(to_int32(value).ok_or_else(invalid)?)?,
+            TYPE_SINT32 => output.write_sint32_no_tag(to_int32(value).ok_or_else(invalid)?)?,
+            TYPE_SFIXED32 => output.write_sfixed32_no_tag(to_int32(value).ok_or_else(invalid)?)?,
+            TYPE_UINT32 => output.write_uint32_no_tag(to_uint32(value).ok_or_else(invalid)?)?,
+            TYPE_FIXED32 => output.write_fixed32_no_tag(to_uint32(value).ok_or_else(invalid)?)?,
+            TYPE_BOOL => output.write_bool_no_tag(value.as_bool().ok_or_else(invalid)?)?,
+            TYPE_STRING => output.write_string_no_tag(value.as_str().ok_or_else(invalid)?)?,
+            TYPE_BYTES => {
+                let bytes = value
+                    .as_str()
+                    .and_then(|s| base64::decode(s).ok())
+                    .ok_or_else(invalid)?;
+                output.write_bytes_no_tag(&bytes)?
+            }
+            TYPE_ENUM => {
+                let values = self.enum_values(field.get_type_name())?;
+                let number = match value {
+                    Value::String(name) => values
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, number)| *number),
+                    value => to_int32(value),
+                };
+                output.write_enum_no_tag(number.ok_or_else(invalid)?)?
+            }
+            TYPE_MESSAGE => {
+                output.write_bytes_no_tag(&self.encode(field.get_type_name(), value)?)?
+            }
+            TYPE_GROUP => return Err(format_err!("groups aren't supported")),
+        }
+        Ok(())
+    }
+
+    /// The entry type of a map field, which protobuf represents as a repeated message.
+    fn map_entry(&self, field: &FieldDescriptorProto) -> Result<Option<&MessageType>, Error> {
+        if !is_repeated(field) || field.get_field_type() != FieldDescriptorProto_Type::TYPE_MESSAGE
+        {
+            return Ok(None);
+        }
+        let message = self.message(field.get_type_name())?;
+        Ok(Some(message).filter(|m| m.map_entry))
+    }
+}
+
+impl MessageType {
+    fn field(&self, number: u32) -> Option<&FieldDescriptorProto> {
+        self.fields.iter().find(|f| f.get_number() as u32 == number)
+    }
+
+    /// Finds a field by its JSON name or its name in the .proto file.
+    fn named(&self, name: &str) -> Option<&FieldDescriptorProto> {
+        self.fields
+            .iter()
+            .find(|f| json_name(f) == name || f.get_name() == name)
+    }
+}
+
+/// The name of a field in JSON, which protoc records as lowerCamelCase.
+fn json_name(field: &FieldDescriptorProto) -> String {
+    if field.has_json_name() {
+        return field.get_json_name().to_owned();
+    }
+    let mut name = String::new();
+    let mut upper = false;
+    for c in field.get_name().chars() {
+        match c {
+            '_' => upper = true,
+            c if upper => {
+                name.extend(c.to_uppercase());
+                upper = false;
+            }
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+/// A map key from JSON, where keys are always strings, as the entry's key type.
+fn map_key(entry: &MessageType, key: &str) -> Result<Value, Error> {
+    use FieldDescriptorProto_Type::*;
+
+    let field = entry
+        .field(1)
+        .ok_or_else(|| format_err!("map entry without a key"))?;
+    let key = match field.get_field_type() {
+        TYPE_STRING | TYPE_INT64 | TYPE_SINT64 | TYPE_SFIXED64 | TYPE_UINT64 | TYPE_FIXED64 => {
+            key.into()
+        }
+        TYPE_BOOL => key
+            .parse::<bool>()
+            .map_err(|_| format_err!("invalid map key {:?}", key))?
+            .into(),
+        _ => Value::Number(
+            key.parse::<Number>()
+                .map_err(|_| format_err!("invalid map key {:?}", key))?,
+        ),
+    };
+    Ok(key)
+}
+
+fn is_repeated(field: &FieldDescriptorProto) -> bool {
+    field.get_label() == FieldDescriptorProto_Label::LABEL_REPEATED
+}
+
+fn is_packable(field: &FieldDescriptorProto) -> bool {
+    wire_type(field) != WireType::WireTypeLengthDelimited
+}
+
+/// Repeated numbers are packed by default in proto3, and only when asked to in proto2.
+fn is_packed(message: &MessageType, field: &FieldDescriptorProto) -> bool {
+    let options = field.get_options();
+    is_packable(field)
+        && if options.has_packed() {
+            options.get_packed()
+        } else {
+            message.proto3
+        }
+}
+
+fn wire_type(field: &FieldDescriptorProto) -> WireType {
+    use FieldDescriptorProto_Type::*;
+
+    match field.get_field_type() {
+        TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => WireType::WireTypeFixed64,
+        TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => WireType::WireTypeFixed32,
+        TYPE_STRING | TYPE_BYTES | TYPE_MESSAGE => WireType::WireTypeLengthDelimited,
+        TYPE_GROUP => WireType::WireTypeStartGroup,
+        _ => WireType::WireTypeVarint,
+    }
+}
+
+fn check_wire_type(field: &FieldDescriptorProto, actual: WireType) -> Result<(), Error> {
+    let expected = wire_type(field);
+    if actual == expected {
+        return Ok(());
+    }
+    Err(format_err!(
+        "field {:?} has wire type {:?}, expected {:?}",
+        field.get_name(),
+        actual,
+        expected
+    ))
+}
+
+/// A float in JSON, where the values JSON can't represent are strings.
+fn float(f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => Value::Number(n),
+        None if f.is_nan() => "NaN".into(),
+        None if f > 0.0 => "Infinity".into(),
+        None => "-Infinity".into(),
+    }
+}
+
+fn to_float(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => match &**s {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+fn to_int(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn to_uint(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn to_int32(value: &Value) -> Option<i32> {
+    to_int(value).and_then(|i| i32::try_from(i).ok())
+}
+
+fn to_uint32(value: &Value) -> Option<u32> {
+    to_uint(value).and_then(|i| u32::try_from(i).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use protobuf::{
+        descriptor::{EnumValueDescriptorProto, FileDescriptorProto},
+        Message as _,
+    };
+
+    use crate::{no_metdata_batches, no_metdata_messages, Message};
+
+    fn field(
+        name: &str,
+        number: i32,
+        field_type: FieldDescriptorProto_Type,
+        type_name: &str,
+        repeated: bool,
+    ) -> FieldDescriptorProto {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name(name.into());
+        field.set_number(number);
+        field.set_field_type(field_type);
+        field.set_type_name(type_name.into());
+        field.set_label(if repeated {
+            FieldDescriptorProto_Label::LABEL_REPEATED
+        } else {
+            FieldDescriptorProto_Label::LABEL_OPTIONAL
+        });
+        field
+    }
+
+    fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+        let mut message = DescriptorProto::new();
+        message.set_name(name.into());
+        message.set_field(fields.into());
+        message
+    }
+
+    /// The descriptor set protoc would write for:
+    ///
+    /// ```text
+    /// syntax = "proto3";
+    /// package dairy;
+    /// enum Milk { COW = 0; GOAT = 1; }
+    /// message Cheese {
+    ///   message Origin { string country = 1; Origin region = 2; }
+    ///   string name = 1;
+    ///   int64 weight = 2;
+    ///   repeated int32 ratings = 3;
+    ///   Milk milk = 4;
+    ///   Origin origin = 5;
+    ///   map<string, int32> stock = 6;
+    ///   bytes rind = 7;
+    ///   double price = 8;
+    ///   repeated string tags = 9;
+    ///   bool raw_milk = 10;
+    /// }
+    /// ```
+    fn descriptor_set() -> Vec<u8> {
+        use FieldDescriptorProto_Type::*;
+
+        let mut milk = EnumDescriptorProto::new();
+        milk.set_name("Milk".into());
+        for (number, name) in ["COW", "GOAT"].iter().enumerate() {
+            let mut value = EnumValueDescriptorProto::new();
+            value.set_name((*name).into());
+            value.set_number(number as i32);
+            milk.mut_value().push(value);
+        }
+
+        let mut stock = message(
+            "StockEntry",
+            vec![
+                field("key", 1, TYPE_STRING, "", false),
+                field("value", 2, TYPE_INT32, "", false),
+            ],
+        );
+        stock.mut_options().set_map_entry(true);
+
+        let mut cheese = message(
+            "Cheese",
+            vec![
+                field("name", 1, TYPE_STRING, "", false),
+                field("weight", 2, TYPE_INT64, "", false),
+                field("ratings", 3, TYPE_INT32, "", true),
+                field("milk", 4, TYPE_ENUM, ".dairy.Milk", false),
+                field("origin", 5, TYPE_MESSAGE, ".dairy.Cheese.Origin", false),
+                field("stock", 6, TYPE_MESSAGE, ".dairy.Cheese.StockEntry", true),
+                field("rind", 7, TYPE_BYTES, "", false),
+                field("price", 8, TYPE_DOUBLE, "", false),
+                field("tags", 9, TYPE_STRING, "", true),
+                field("raw_milk", 10, TYPE_BOOL, "", false),
+            ],
+        );
+        cheese.mut_nested_type().push(message(
+            "Origin",
+            vec![
+                field("country", 1, TYPE_STRING, "", false),
+                field("region", 2, TYPE_MESSAGE, ".dairy.Cheese.Origin", false),
+            ],
+        ));
+        cheese.mut_nested_type().push(stock);
+
+        let mut file = FileDescriptorProto::new();
+        file.set_name("dairy.proto".into());
+        file.set_package("dairy".into());
+        file.set_syntax("proto3".into());
+        file.mut_enum_type().push(milk);
+        file.mut_message_type().push(cheese);
+
+        let mut set = FileDescriptorSet::new();
+        set.mut_file().push(file);
+        set.write_to_bytes().unwrap()
+    }
+
+    fn protobuf(operator: &str, message: &str) -> Protobuf {
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, descriptor_set()).unwrap();
+        let config = format!(
+            "operator: {}\ndescriptor_set: {}\nmessage: {}",
+            operator,
+            path.display(),
+            message
+        );
+        let protobuf = serde_yaml::from_str(&config);
+        fs::remove_file(path).unwrap();
+        protobuf.unwrap()
+    }
+
+    #[test]
+    fn process_protobuf_from_json_test() {
+        let batches = crate::run_processor!(
+            protobuf("from_json", "dairy.Cheese"),
+            no_metdata_batches![no_metdata_messages![
+                br#"{"name":"brie","weight":"300","ratings":[1,2],"milk":"GOAT","rawMilk":true}"#,
+                br#"{"name":"brie","bacon":true}"#
+            ]]
+        );
+        let messages = &batches[0].messages;
+
+        assert_eq!(
+            messages[0].data,
+            vec![
+                0x0a, 4, b'b', b'r', b'i', b'e', // name
+                0x10, 0xac, 0x02, // weight
+                0x1a, 2, 1, 2, // packed ratings
+                0x20, 1, // milk
+                0x50, 1, // raw_milk
+            ]
+        );
+        assert_eq!(messages[1].data, br#"{"name":"brie","bacon":true}"#);
+        assert_eq!(
+            messages[1].metadata["protobuf_error"],
+            r#"dairy.Cheese has no field "bacon""#
+        );
+    }
+
+    #[test]
+    fn process_protobuf_to_json_test() {
+        let unpacked = vec![0x18, 5, 0x18, 6, 0x58, 1];
+        let batches = crate::run_processor!(
+            protobuf("to_json", ".dairy.Cheese"),
+            no_metdata_batches![vec![
+                Message {
+                    data: vec![0x0a, 4, b'f', b'e', b't', b'a', 0x1a, 2, 3, 4, 0x20, 7],
+                    ..Default::default()
+                },
+                Message {
+                    data: unpacked,
+                    ..Default::default()
+                },
+                Message {
+                    data: vec![0x0a, 9],
+                    ..Default::default()
+                }
+            ]]
+        );
+        let messages = &batches[0].messages;
+
+        // Unknown enum values are kept as numbers, and unknown fields are skipped.
+        assert_eq!(
+            messages[0].data,
+            br#"{"milk":7,"name":"feta","ratings":[3,4]}"#.to_vec()
+        );
+        assert_eq!(messages[1].data, br#"{"ratings":[5,6]}"#.to_vec());
+        assert_eq!(messages[2].data, vec![0x0a, 9]);
+        assert!(messages[2].metadata.contains_key("protobuf_error"));
+    }
+
+    #[test]
+    fn protobuf_round_trip_test() {
+        let types = Types::parse(&descriptor_set()).unwrap();
+        let json = serde_json::json!({
+            "name": "comté",
+            "weight": "-9007199254740993",
+            "ratings": [5, -1],
+            "milk": "COW",
+            "origin": {"country": "france"},
+            "stock": {"paris": 3, "lyon": 0},
+            "rind": base64::encode(b"natural"),
+            "price": "NaN",
+            "tags": ["hard", "nutty"],
+            "rawMilk": false,
+        });
+
+        let encoded = types.encode("dairy.Cheese", &json).unwrap();
+        assert_eq!(types.decode("dairy.Cheese", &encoded).unwrap(), json);
+
+        // Field names from the .proto file and numbers for 64 bit integers are accepted too.
+        let encoded = types
+            .encode(
+                "dairy.Cheese",
+                &serde_json::json!({"raw_milk": true, "weight": 12, "milk": 1}),
+            )
+            .unwrap();
+        assert_eq!(
+            types.decode("dairy.Cheese", &encoded).unwrap(),
+            serde_json::json!({"rawMilk": true, "weight": "12", "milk": "GOAT"})
+        );
+    }
+
+    #[test]
+    fn protobuf_errors_test() {
+        let types = Types::parse(&descriptor_set()).unwrap();
+
+        for json in &[
+            serde_json::json!([]),
+            serde_json::json!({"ratings": 1}),
+            serde_json::json!({"ratings": ["one"]}),
+            serde_json::json!({"milk": "SHEEP"}),
+            serde_json::json!({"weight": "heavy"}),
+            serde_json::json!({"rind": "not base64!"}),
+            serde_json::json!({"origin": {"county": "kent"}}),
+        ] {
+            assert!(types.encode("dairy.Cheese", json).is_err(), "{}", json);
+        }
+
+        // A string where a number should be.
+        assert!(types.decode("dairy.Cheese", &[0x12, 1, b'a']).is_err());
+        assert!(types
+            .decode("dairy.Cheese", &[0x0a, 2, 0xff, 0xfe])
+            .is_err());
+
+        let nested = |depth| {
+            let origin = (0..depth).fold(
+                serde_json::json!({}),
+                |origin, _| serde_json::json!({ "region": origin }),
+            );
+            let json = serde_json::json!({ "origin": origin });
+            types.encode("dairy.Cheese", &json).unwrap()
+        };
+        assert!(types.decode("dairy.Cheese", &nested(99)).is_ok());
+        assert_eq!(
+            types
+                .decode("dairy.Cheese", &nested(100))
+                .unwrap_err()
+                .to_string(),
+            "message nesting is deeper than 100"
+        );
+
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, descriptor_set()).unwrap();
+        let config = format!(
+            "operator: to_json\ndescriptor_set: {}\nmessage: dairy.Bacon",
+            path.display()
+        );
+        assert!(serde_yaml::from_str::<Protobuf>(&config).is_err());
+        fs::remove_file(path).unwrap();
+        assert!(serde_yaml::from_str::<Protobuf>(
+            "operator: to_json\ndescriptor_set: missing.pb\nmessage: dairy.Cheese"
+        )
+        .is_err());
+    }
+}
@@ -3,7 +3,6 @@ use std::str;
 use futures::stream::Stream;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use typetag::serde;
 
 use crate::{Message, ProcessHandler, Processor};
 
@@ -15,7 +14,7 @@ struct RegexReplace {
 
 #[typetag::serde(name = "regex_replace")]
 impl Processor for RegexReplace {
-    fn create<'a>(&self) -> ProcessHandler {
+    fn create(&self) -> ProcessHandler {
         let re = Regex::new(&self.re).unwrap();
         let rep = self.rep.to_owned();
 
@@ -51,7 +50,6 @@ mod replace_tests {
                 RegexReplace {
                     re: $re.into(),
                     rep: $rep.into(),
-                    ..RegexReplace::default()
                 },
                 $input
             )
@@ -100,7 +98,7 @@ struct RegexSplit {
 
 #[typetag::serde(name = "regex_split")]
 impl Processor for RegexSplit {
-    fn create<'a>(&self) -> ProcessHandler {
+    fn create(&self) -> ProcessHandler {
         let re = Regex::new(&self.re).unwrap();
 
         Box::new(move |batches| {
@@ -139,13 +137,7 @@ mod split_tests {
 
     macro_rules! regex_split {
         ( $re:expr, $input:expr ) => {{
-            $crate::run_processor!(
-                RegexSplit {
-                    re: $re.into(),
-                    ..RegexSplit::default()
-                },
-                $input
-            )
+            $crate::run_processor!(RegexSplit { re: $re.into() }, $input)
         }};
     }
 
@@ -185,7 +177,7 @@ struct RegexSelect {
 
 #[typetag::serde(name = "regex_select")]
 impl Processor for RegexSelect {
-    fn create<'a>(&self) -> ProcessHandler {
+    fn create(&self) -> ProcessHandler {
         let re = Regex::new(&self.re).unwrap();
 
         Box::new(move |batches| {
@@ -224,13 +216,7 @@ mod select_tests {
 
     macro_rules! regex_select {
         ( $re:expr, $input:expr ) => {{
-            $crate::run_processor!(
-                RegexSelect {
-                    re: $re.into(),
-                    ..RegexSelect::default()
-                },
-                $input
-            )
+            $crate::run_processor!(RegexSelect { re: $re.into() }, $input)
         }};
     }
 
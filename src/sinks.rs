@@ -3,7 +3,6 @@ use std::str;
 use futures::future::ok;
 use futures::stream::Stream;
 use serde::{Deserialize, Serialize};
-use typetag::serde;
 
 use crate::{Sink, WriteHandler};
 
@@ -25,3 +24,620 @@ impl Sink for StdOut {
         })
     }
 }
+
+#[cfg(feature = "http_server")]
+mod http_server {
+    use std::{
+        collections::VecDeque,
+        convert::TryFrom,
+        io::{self, Read, Write},
+        net::{TcpListener, TcpStream},
+        str,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use failure::{format_err, Error};
+    use futures::{
+        future::{err, ok},
+        stream::Stream,
+    };
+    use lazy_static::lazy_static;
+    use log::{debug, error};
+    use openssl::ssl::SslAcceptor;
+    use prometheus::{IntCounter, Opts};
+    use serde::{Deserialize, Serialize};
+    use tiny_http::ReadWrite;
+    use tungstenite::{
+        handshake::server::create_response, http::Request, protocol::Role, WebSocket,
+    };
+
+    use crate::{
+        tls::{Acceptor, Tls},
+        Sink, WriteHandler,
+    };
+
+    /// How long a client waits for a message before checking whether it has gone away.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// How long that check waits for the client to say something.
+    const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+    /// How long a new client gets to finish the TLS handshake and send its request.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const MAX_HEAD_SIZE: usize = 8192;
+
+    lazy_static! {
+        static ref DROPPED: IntCounter = {
+            let counter = IntCounter::with_opts(Opts::new(
+                "nekton_http_server_output_dropped_total",
+                "Messages dropped by the http_server output for slow clients.",
+            ))
+            .unwrap();
+            prometheus::register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+    }
+
+    /// Serves messages to websocket and server-sent events clients. The address is bound when
+    /// the config is loaded, so one that is taken fails at startup.
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(try_from = "HttpServerConfig", into = "HttpServerConfig")]
+    struct HttpServer {
+        config: HttpServerConfig,
+        listener: Arc<TcpListener>,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct HttpServerConfig {
+        address: String,
+        #[serde(default = "default_ws_path")]
+        ws_path: String,
+        #[serde(default = "default_sse_path")]
+        sse_path: String,
+        /// Messages buffered per client before `drop_policy` applies.
+        #[serde(default = "default_buffer_size")]
+        buffer_size: usize,
+        #[serde(default)]
+        drop_policy: DropPolicy,
+        #[serde(default)]
+        tls: Option<Tls>,
+    }
+
+    impl TryFrom<HttpServerConfig> for HttpServer {
+        type Error = Error;
+
+        fn try_from(config: HttpServerConfig) -> Result<HttpServer, Error> {
+            let listener = TcpListener::bind(&config.address)
+                .map_err(|e| format_err!("failed to bind to {}: {}", config.address, e))?;
+            Ok(HttpServer {
+                config,
+                listener: Arc::new(listener),
+            })
+        }
+    }
+
+    impl From<HttpServer> for HttpServerConfig {
+        fn from(server: HttpServer) -> HttpServerConfig {
+            server.config
+        }
+    }
+
+    fn default_ws_path() -> String {
+        "/ws".to_owned()
+    }
+
+    fn default_sse_path() -> String {
+        "/sse".to_owned()
+    }
+
+    fn default_buffer_size() -> usize {
+        1024
+    }
+
+    #[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum DropPolicy {
+        #[default]
+        DropNewest,
+        DropOldest,
+        Disconnect,
+    }
+
+    #[derive(Default)]
+    struct Client {
+        queue: Mutex<VecDeque<Arc<Vec<u8>>>>,
+        ready: Condvar,
+        closed: AtomicBool,
+    }
+
+    impl Client {
+        fn push(&self, data: Arc<Vec<u8>>, buffer_size: usize, drop_policy: DropPolicy) {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() >= buffer_size {
+                DROPPED.inc();
+                match drop_policy {
+                    DropPolicy::DropNewest => return,
+                    DropPolicy::DropOldest => {
+                        queue.pop_front();
+                    }
+                    DropPolicy::Disconnect => {
+                        self.close();
+                        return;
+                    }
+                }
+            }
+            queue.push_back(data);
+            self.ready.notify_one();
+        }
+
+        /// Waits up to `timeout` for a message, `None` if there is none or the client is closed.
+        fn pop(&self, timeout: Duration) -> Option<Arc<Vec<u8>>> {
+            let queue = self.queue.lock().unwrap();
+            let (mut queue, _) = self
+                .ready
+                .wait_timeout_while(queue, timeout, |q| q.is_empty() && !self.is_closed())
+                .unwrap();
+            if self.is_closed() {
+                return None;
+            }
+            queue.pop_front()
+        }
+
+        fn close(&self) {
+            self.closed.store(true, Ordering::SeqCst);
+            self.ready.notify_one();
+        }
+
+        fn is_closed(&self) -> bool {
+            self.closed.load(Ordering::SeqCst)
+        }
+    }
+
+    type Clients = Arc<Mutex<Vec<Arc<Client>>>>;
+
+    impl HttpServer {
+        fn serve(&self, clients: Clients) -> Result<(), Error> {
+            let listener = self.listener.try_clone()?;
+            let config = &self.config;
+            let mut acceptor = config.tls.as_ref().map(Acceptor::new).transpose()?;
+            let paths = Arc::new((config.ws_path.clone(), config.sse_path.clone()));
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            error!("Failed to accept client: {}", e);
+                            continue;
+                        }
+                    };
+                    let tls = acceptor.as_mut().map(Acceptor::current);
+                    let (paths, clients) = (paths.clone(), clients.clone());
+
+                    thread::spawn(move || {
+                        let connection = match accept(stream, tls, &paths.0, &paths.1) {
+                            Ok(Some(connection)) => connection,
+                            Ok(None) => return,
+                            Err(e) => {
+                                error!("Failed to accept client: {}", e);
+                                return;
+                            }
+                        };
+
+                        let client = Arc::new(Client::default());
+                        {
+                            let mut clients = clients.lock().unwrap();
+                            clients.retain(|c| !c.is_closed());
+                            clients.push(client.clone());
+                        }
+                        connection.stream(&client);
+                    });
+                }
+            });
+
+            Ok(())
+        }
+    }
+
+    enum Connection {
+        WebSocket(Box<WebSocket<Box<dyn ReadWrite + Send>>>),
+        Sse(Box<dyn ReadWrite + Send>),
+    }
+
+    impl Connection {
+        /// Sends the client its messages until either side closes, listening for the client
+        /// going away whenever there is nothing to send.
+        fn stream(mut self, client: &Client) {
+            while !client.is_closed() {
+                let result = match client.pop(POLL_INTERVAL) {
+                    Some(data) => self.send(&data),
+                    None => self.poll(),
+                };
+                if let Err(e) = result {
+                    debug!("http_server output client disconnected: {}", e);
+                    break;
+                }
+            }
+            client.close();
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+            match self {
+                Connection::WebSocket(socket) => {
+                    let message = match String::from_utf8(data.to_vec()) {
+                        Ok(text) => tungstenite::Message::Text(text),
+                        Err(e) => tungstenite::Message::Binary(e.into_bytes()),
+                    };
+                    socket.write_message(message)?;
+                }
+                Connection::Sse(stream) => {
+                    let mut event = Vec::new();
+                    for line in data.split(|b| b == &b'\n') {
+                        event.extend_from_slice(b"data: ");
+                        event.extend_from_slice(line);
+                        event.push(b'\n');
+                    }
+                    event.push(b'\n');
+                    stream.write_all(&event)?;
+                    stream.flush()?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Reads and drops whatever the client sent, failing once it has closed the connection.
+        fn poll(&mut self) -> Result<(), Error> {
+            match self {
+                Connection::WebSocket(socket) => match socket.read_message() {
+                    Ok(tungstenite::Message::Close(_)) => Err(format_err!("closed by client")),
+                    Ok(_) => Ok(()),
+                    Err(tungstenite::Error::Io(ref e)) if timed_out(e) => Ok(()),
+                    Err(e) => Err(e.into()),
+                },
+                Connection::Sse(stream) => match stream.read(&mut [0; 512]) {
+                    Ok(0) => Err(format_err!("closed by client")),
+                    Ok(_) => Ok(()),
+                    Err(ref e) if timed_out(e) => Ok(()),
+                    Err(e) => Err(e.into()),
+                },
+            }
+        }
+    }
+
+    fn timed_out(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+    }
+
+    /// Answers the request on a new connection, returning the connection if it is a client.
+    fn accept(
+        stream: TcpStream,
+        tls: Option<SslAcceptor>,
+        ws_path: &str,
+        sse_path: &str,
+    ) -> Result<Option<Connection>, Error> {
+        let socket = stream.try_clone()?;
+        socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut stream: Box<dyn ReadWrite + Send> = match tls {
+            Some(tls) => Box::new(
+                tls.accept(stream)
+                    .map_err(|e| format_err!("TLS handshake failed: {}", e))?,
+            ),
+            None => Box::new(stream),
+        };
+
+        let request = read_request(&mut stream)?;
+        let connection = if request.uri().path() == ws_path {
+            let accept = match create_response(&request) {
+                Ok(response) => response,
+                Err(e) => {
+                    respond(&mut stream, "400 Bad Request", &e.to_string())?;
+                    return Ok(None);
+                }
+            };
+            let key = accept
+                .headers()
+                .get("sec-websocket-accept")
+                .map(|v| v.as_bytes())
+                .unwrap_or_default();
+            stream.write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Accept: ",
+            )?;
+            stream.write_all(key)?;
+            stream.write_all(b"\r\n\r\n")?;
+            stream.flush()?;
+            Connection::WebSocket(Box::new(WebSocket::from_raw_socket(
+                stream,
+                Role::Server,
+                None,
+            )))
+        } else if request.uri().path() == sse_path {
+            stream.write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                  Content-Type: text/event-stream\r\n\
+                  Cache-Control: no-cache\r\n\
+                  Connection: keep-alive\r\n\r\n",
+            )?;
+            stream.flush()?;
+            Connection::Sse(stream)
+        } else {
+            respond(&mut stream, "404 Not Found", "")?;
+            return Ok(None);
+        };
+
+        // From here on reads only check whether the client is still there.
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(Some(connection))
+    }
+
+    /// Reads the request line and headers a byte at a time, leaving whatever follows unread.
+    fn read_request(stream: &mut impl Read) -> Result<Request<()>, Error> {
+        let (mut head, mut byte) = (Vec::new(), [0]);
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(format_err!("connection closed mid request"));
+            }
+            if head.len() == MAX_HEAD_SIZE {
+                return Err(format_err!("request head is over {} bytes", MAX_HEAD_SIZE));
+            }
+            head.push(byte[0]);
+        }
+
+        let mut lines = str::from_utf8(&head)?.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let mut request = Request::builder()
+            .method(request_line.next().unwrap_or_default())
+            .uri(request_line.next().unwrap_or_default());
+        for line in lines {
+            let mut header = line.splitn(2, ':');
+            if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                request = request.header(name.trim(), value.trim());
+            }
+        }
+        Ok(request.body(())?)
+    }
+
+    fn respond(stream: &mut impl Write, status: &str, body: &str) -> Result<(), Error> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        Ok(stream.flush()?)
+    }
+
+    #[typetag::serde(name = "http_server")]
+    impl Sink for HttpServer {
+        fn create(&self) -> WriteHandler {
+            let clients: Clients = Arc::default();
+            if let Err(e) = self.serve(clients.clone()) {
+                let e = e.to_string();
+                return Box::new(move |_| Box::new(err(format_err!("{}", e))));
+            }
+
+            let (buffer_size, drop_policy) = (self.config.buffer_size, self.config.drop_policy);
+
+            Box::new(move |batches| {
+                let clients = clients.clone();
+                let result = batches.for_each(move |batch| {
+                    let mut clients = clients.lock().unwrap();
+                    clients.retain(|c| !c.is_closed());
+                    for message in batch.messages {
+                        let data = Arc::new(message.data);
+                        for client in clients.iter() {
+                            client.push(data.clone(), buffer_size, drop_policy);
+                        }
+                    }
+                    ok(())
+                });
+
+                Box::new(result)
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use std::{
+            io::{BufRead, BufReader},
+            net::{TcpListener, TcpStream},
+            time::Duration,
+        };
+
+        use futures::Future;
+
+        use crate::{no_metdata_batches, no_metdata_messages, Message};
+
+        fn server(address: &str) -> HttpServer {
+            HttpServer::try_from(HttpServerConfig {
+                address: address.to_owned(),
+                ws_path: default_ws_path(),
+                sse_path: default_sse_path(),
+                buffer_size: default_buffer_size(),
+                drop_policy: DropPolicy::default(),
+                tls: None,
+            })
+            .unwrap()
+        }
+
+        fn start(address: &str) -> WriteHandler {
+            let handler = server(address).create();
+            thread::sleep(Duration::from_millis(100));
+            handler
+        }
+
+        fn free_address() -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        }
+
+        fn write(handler: &WriteHandler, messages: Vec<Message>) {
+            use failure::format_err;
+            use futures::stream;
+
+            handler(Box::new(
+                stream::iter_ok::<_, ()>(no_metdata_batches![messages])
+                    .map_err(|_| format_err!("wtf")),
+            ))
+            .wait()
+            .unwrap();
+        }
+
+        #[test]
+        fn http_server_sse_test() {
+            let address = free_address();
+            let handler = start(&address);
+
+            let mut stream = TcpStream::connect(&address).unwrap();
+            write!(stream, "GET /sse HTTP/1.1\r\nHost: {}\r\n\r\n", address).unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                lines.push(line);
+            }
+            assert!(lines[0].starts_with("HTTP/1.1 200"));
+            assert!(lines.contains(&"Content-Type: text/event-stream\r\n".to_owned()));
+
+            thread::sleep(Duration::from_millis(100));
+            write(&handler, no_metdata_messages![b"cheese", b"goat\nbrie"]);
+
+            let mut event = String::new();
+            for _ in 0..5 {
+                reader.read_line(&mut event).unwrap();
+            }
+            assert_eq!(event, "data: cheese\n\ndata: goat\ndata: brie\n\n");
+        }
+
+        #[test]
+        fn http_server_websocket_test() {
+            let address = free_address();
+            let handler = start(&address);
+
+            let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws", address)).unwrap();
+
+            thread::sleep(Duration::from_millis(100));
+            write(&handler, no_metdata_messages![b"cheese", b"\xff"]);
+
+            assert_eq!(
+                socket.read_message().unwrap(),
+                tungstenite::Message::Text("cheese".into())
+            );
+            assert_eq!(
+                socket.read_message().unwrap(),
+                tungstenite::Message::Binary(vec![0xff])
+            );
+        }
+
+        #[test]
+        fn http_server_client_close_test() {
+            let address = free_address();
+            let clients: Clients = Arc::default();
+            server(&address).serve(clients.clone()).unwrap();
+
+            let (mut socket, _) = tungstenite::connect(format!("ws://{}/ws", address)).unwrap();
+            let mut stream = TcpStream::connect(&address).unwrap();
+            write!(stream, "GET /sse HTTP/1.1\r\nHost: {}\r\n\r\n", address).unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            let closed = || {
+                let clients = clients.lock().unwrap();
+                clients.iter().filter(|c| c.is_closed()).count()
+            };
+            assert_eq!(clients.lock().unwrap().len(), 2);
+            assert_eq!(closed(), 0);
+
+            // Neither client is sent anything, their closes are read.
+            socket.close(None).unwrap();
+            drop(stream);
+            thread::sleep(POLL_INTERVAL * 3);
+
+            assert_eq!(closed(), 2);
+        }
+
+        #[test]
+        fn http_server_not_found_test() {
+            let address = free_address();
+            let _handler = start(&address);
+
+            let mut stream = TcpStream::connect(&address).unwrap();
+            write!(stream, "GET /cheese HTTP/1.1\r\nHost: {}\r\n\r\n", address).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        }
+
+        #[test]
+        fn http_server_bind_error_test() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+
+            let config = format!("type: http_server\naddress: {}", address);
+            let result = serde_yaml::from_str::<Box<dyn Sink>>(&config);
+
+            assert!(result
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with(&format!("failed to bind to {}", address)));
+        }
+
+        #[test]
+        fn http_server_head_size_test() {
+            let address = free_address();
+            let _handler = start(&address);
+
+            let mut stream = TcpStream::connect(&address).unwrap();
+            write!(stream, "GET /sse HTTP/1.1\r\nHost: {}\r\n", address).unwrap();
+            let cheese = "cheese".repeat(MAX_HEAD_SIZE / 6);
+            let _ = write!(stream, "X-Cheese: {}\r\n\r\n", cheese);
+
+            // The request is dropped before it is answered.
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response);
+            assert!(response.is_empty());
+        }
+
+        fn queued(drop_policy: DropPolicy) -> (Vec<Vec<u8>>, bool) {
+            let client = Client::default();
+            for data in &[b"a", b"b", b"c"] {
+                client.push(Arc::new(data.to_vec()), 2, drop_policy);
+            }
+            let queue = client.queue.lock().unwrap();
+            (
+                queue.iter().map(|d| d.to_vec()).collect(),
+                client.is_closed(),
+            )
+        }
+
+        #[test]
+        fn http_server_drop_policy_test() {
+            assert_eq!(
+                queued(DropPolicy::DropNewest),
+                (vec![b"a".to_vec(), b"b".to_vec()], false)
+            );
+            assert_eq!(
+                queued(DropPolicy::DropOldest),
+                (vec![b"b".to_vec(), b"c".to_vec()], false)
+            );
+            assert!(queued(DropPolicy::Disconnect).1);
+        }
+    }
+}
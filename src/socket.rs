@@ -0,0 +1,1001 @@
+use std::{
+    convert::TryFrom,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    path::Path,
+    process, str,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+#[cfg(unix)]
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::{UnixDatagram, UnixListener, UnixStream},
+    },
+};
+
+use failure::{format_err, Error};
+use futures::{future::result, Future, Stream};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use prometheus::{IntCounter, Opts};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backoff::{default_max_retries, Backoff},
+    Message, MessageBatch, Sink, Source, Transaction, TransactionHandler, WriteHandler,
+};
+
+/// Messages received but not yet handed to the pipeline. Streams stop being read once it is
+/// full, while datagrams are dropped.
+const BUFFER_SIZE: usize = 1024;
+
+lazy_static! {
+    static ref DROPPED: IntCounter = {
+        let counter = IntCounter::with_opts(Opts::new(
+            "nekton_socket_server_dropped_total",
+            "Datagrams dropped by socket inputs while the pipeline is behind.",
+        ))
+        .unwrap();
+        prometheus::register(Box::new(counter.clone())).unwrap();
+        counter
+    };
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Network {
+    Tcp,
+    Udp,
+    /// A unix domain stream socket, `address` is the socket path.
+    #[cfg(unix)]
+    Unix,
+    /// A unix domain datagram socket, `address` is the socket path.
+    #[cfg(unix)]
+    Unixgram,
+}
+
+impl Network {
+    fn is_datagram(self) -> bool {
+        match self {
+            Network::Udp => true,
+            #[cfg(unix)]
+            Network::Unixgram => true,
+            _ => false,
+        }
+    }
+
+    /// Each datagram is one message, so a framing set for one is a mistake rather than something
+    /// to ignore.
+    fn check_framing(self, framing: &Framing) -> Result<(), Error> {
+        if self.is_datagram() && *framing != Framing::default() {
+            return Err(format_err!(
+                "framing can't be set for a datagram network, each datagram is one message"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How a byte stream is cut into messages, with frames longer than `max_frame_size` rejected.
+/// Datagram networks don't take one, each datagram is one message.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Framing {
+    /// Newline terminated, with any trailing `\r` removed.
+    Lines {
+        #[serde(default = "default_max_frame_size")]
+        max_frame_size: usize,
+    },
+    Delimiter {
+        delimiter: String,
+        #[serde(default = "default_max_frame_size")]
+        max_frame_size: usize,
+    },
+    /// A 4 byte big endian length followed by that many bytes.
+    LengthPrefixed {
+        #[serde(default = "default_max_frame_size")]
+        max_frame_size: usize,
+    },
+    /// RFC 6587 octet counting, `<length> <message>`, falling back to newline terminated frames.
+    OctetCounting {
+        #[serde(default = "default_max_frame_size")]
+        max_frame_size: usize,
+    },
+}
+
+pub(crate) fn default_max_frame_size() -> usize {
+    1024 * 1024
+}
+
+impl Default for Framing {
+    fn default() -> Framing {
+        Framing::Lines {
+            max_frame_size: default_max_frame_size(),
+        }
+    }
+}
+
+fn frame_too_large(length: usize, max_frame_size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes is over {}", length, max_frame_size),
+    )
+}
+
+impl Framing {
+    /// Reads the next frame, or `None` once the reader is exhausted.
+    pub(crate) fn read(&self, reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+        let (delimiter, max_frame_size) = match self {
+            Framing::Lines { max_frame_size } => (b"\n".as_ref(), *max_frame_size),
+            Framing::OctetCounting { max_frame_size } => {
+                if !reader.fill_buf()?.first().is_some_and(u8::is_ascii_digit) {
+                    (b"\n".as_ref(), *max_frame_size)
+                } else {
+                    // Enough for any count that fits in a usize and the space after it.
+                    let mut length = Vec::new();
+                    reader.by_ref().take(21).read_until(b' ', &mut length)?;
+                    let length: usize = str::from_utf8(&length)
+                        .ok()
+                        .and_then(|l| l.strip_suffix(' '))
+                        .and_then(|l| l.parse().ok())
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "invalid octet count")
+                        })?;
+                    if length > *max_frame_size {
+                        return Err(frame_too_large(length, *max_frame_size));
+                    }
+                    let mut data = vec![0; length];
+                    reader.read_exact(&mut data)?;
+                    return Ok(Some(data));
+                }
+            }
+            Framing::Delimiter {
+                delimiter,
+                max_frame_size,
+            } => (delimiter.as_bytes(), *max_frame_size),
+            Framing::LengthPrefixed { max_frame_size } => {
+                if reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let mut length = [0; 4];
+                reader.read_exact(&mut length)?;
+                let length = u32::from_be_bytes(length) as usize;
+                if length > *max_frame_size {
+                    return Err(frame_too_large(length, *max_frame_size));
+                }
+                let mut data = vec![0; length];
+                reader.read_exact(&mut data)?;
+                return Ok(Some(data));
+            }
+        };
+
+        let last = *delimiter
+            .last()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty delimiter"))?;
+        // A frame is read up to its delimiter at most, so a missing one can't exhaust memory.
+        let limit = max_frame_size + delimiter.len();
+        let mut data = Vec::new();
+        loop {
+            let remaining = (limit - data.len()) as u64;
+            if reader
+                .by_ref()
+                .take(remaining)
+                .read_until(last, &mut data)?
+                == 0
+            {
+                return Ok(if data.is_empty() { None } else { Some(data) });
+            }
+            if data.ends_with(delimiter) {
+                data.truncate(data.len() - delimiter.len());
+                if let Framing::Lines { .. } | Framing::OctetCounting { .. } = self {
+                    if data.ends_with(b"\r") {
+                        data.pop();
+                    }
+                }
+                return Ok(Some(data));
+            }
+            if data.len() == limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("frame is over {} bytes", max_frame_size),
+                ));
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::with_capacity(data.len() + 4);
+        match self {
+            Framing::Lines { .. } => {
+                framed.extend_from_slice(data);
+                framed.push(b'\n');
+            }
+            Framing::Delimiter { delimiter, .. } => {
+                framed.extend_from_slice(data);
+                framed.extend_from_slice(delimiter.as_bytes());
+            }
+            Framing::LengthPrefixed { .. } => {
+                framed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                framed.extend_from_slice(data);
+            }
+            Framing::OctetCounting { .. } => {
+                framed.extend_from_slice(format!("{} ", data.len()).as_bytes());
+                framed.extend_from_slice(data);
+            }
+        }
+        framed
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "SocketServerConfig")]
+pub(crate) struct SocketServer {
+    pub(crate) network: Network,
+    pub(crate) address: String,
+    #[serde(default)]
+    pub(crate) framing: Framing,
+    /// Octal file mode applied to unix socket paths, such as `"0660"`.
+    #[serde(default)]
+    pub(crate) permissions: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SocketServerConfig {
+    network: Network,
+    address: String,
+    #[serde(default)]
+    framing: Framing,
+    #[serde(default)]
+    permissions: Option<String>,
+}
+
+impl TryFrom<SocketServerConfig> for SocketServer {
+    type Error = Error;
+
+    fn try_from(config: SocketServerConfig) -> Result<SocketServer, Error> {
+        config.network.check_framing(&config.framing)?;
+        Ok(SocketServer {
+            network: config.network,
+            address: config.address,
+            framing: config.framing,
+            permissions: config.permissions,
+        })
+    }
+}
+
+impl SocketServer {
+    fn listen_tcp(&self, tx: SyncSender<Message>) -> Result<(), Error> {
+        let listener = TcpListener::bind(&self.address)?;
+        let framing = self.framing.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let remote_addr = stream
+                            .peer_addr()
+                            .map(|a| a.to_string())
+                            .unwrap_or_default();
+                        read_stream(framing.clone(), stream, remote_addr, tx.clone());
+                    }
+                    Err(e) => warn!("Failed to accept connection: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn listen_udp(&self, tx: SyncSender<Message>) -> Result<(), Error> {
+        let socket = UdpSocket::bind(&self.address)?;
+
+        thread::spawn(move || {
+            let mut buffer = vec![0; 65_536];
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((length, remote_addr)) => {
+                        let remote_addr = remote_addr.to_string();
+                        if !read_datagram(&buffer[..length], &remote_addr, &tx) {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to receive datagram: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(&self, tx: SyncSender<Message>) -> Result<(), Error> {
+        self.remove_stale_socket()?;
+        let listener = self.bind_unix(|path| UnixListener::bind(path))?;
+        let framing = self.framing.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let remote_addr = unix_remote_addr(stream.peer_addr().ok());
+                        read_stream(framing.clone(), stream, remote_addr, tx.clone());
+                    }
+                    Err(e) => warn!("Failed to accept connection: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn listen_unixgram(&self, tx: SyncSender<Message>) -> Result<(), Error> {
+        self.remove_stale_socket()?;
+        let socket = self.bind_unix(|path| UnixDatagram::bind(path))?;
+
+        thread::spawn(move || {
+            let mut buffer = vec![0; 65_536];
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((length, remote_addr)) => {
+                        let remote_addr = unix_remote_addr(Some(remote_addr));
+                        if !read_datagram(&buffer[..length], &remote_addr, &tx) {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to receive datagram: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// A socket file left behind by a previous run would make binding fail, but one that still
+    /// has a server behind it is left alone.
+    #[cfg(unix)]
+    fn remove_stale_socket(&self) -> Result<(), Error> {
+        match fs::symlink_metadata(&self.address) {
+            Ok(metadata) if metadata.file_type().is_socket() => (),
+            _ => return Ok(()),
+        }
+        let probe = match self.network {
+            Network::Unixgram => UnixDatagram::unbound().and_then(|s| s.connect(&self.address)),
+            _ => UnixStream::connect(&self.address).map(|_| ()),
+        };
+        match probe {
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                Ok(fs::remove_file(&self.address)?)
+            }
+            Ok(()) => Err(format_err!("{} is already in use", self.address)),
+            Err(e) => Err(format_err!("failed to check {}: {}", self.address, e)),
+        }
+    }
+
+    /// Binds the socket in a private directory next to `address` and moves it into place once
+    /// `permissions` are applied, so it is never reachable with a looser mode.
+    #[cfg(unix)]
+    fn bind_unix<T>(&self, bind: impl Fn(&Path) -> io::Result<T>) -> Result<T, Error> {
+        let address = Path::new(&self.address);
+        let mode = match &self.permissions {
+            Some(permissions) => u32::from_str_radix(permissions, 8)
+                .map_err(|_| format_err!("invalid permissions {:?}", permissions))?,
+            None => return Ok(bind(address)?),
+        };
+
+        let name = address
+            .file_name()
+            .ok_or_else(|| format_err!("invalid socket path {:?}", self.address))?;
+        let private =
+            address.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+        DirBuilder::new().mode(0o700).create(&private)?;
+        let staged = private.join("socket");
+
+        let bound = bind(&staged).map_err(Error::from).and_then(|socket| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+            fs::rename(&staged, address)?;
+            Ok(socket)
+        });
+        if let Err(e) = fs::remove_dir_all(&private) {
+            warn!("Failed to remove {:?}: {}", private, e);
+        }
+        bound
+    }
+}
+
+/// Reads frames from a connection on its own thread until it closes.
+fn read_stream(
+    framing: Framing,
+    stream: impl Read + Send + 'static,
+    remote_addr: String,
+    tx: SyncSender<Message>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        loop {
+            match framing.read(&mut reader) {
+                Ok(Some(data)) => {
+                    if tx.send(message(data, &remote_addr)).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Connection from {} failed: {}", remote_addr, e);
+                    break;
+                }
+            }
+        }
+        debug!("Connection from {} closed", remote_addr);
+    });
+}
+
+/// Sends a datagram as one message, as RFC 5426 has it for syslog, dropping it if the pipeline
+/// is behind. Returns false once the receiver has gone.
+fn read_datagram(datagram: &[u8], remote_addr: &str, tx: &SyncSender<Message>) -> bool {
+    match tx.try_send(message(datagram.to_vec(), remote_addr)) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            DROPPED.inc();
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// Unix clients are usually unbound, in which case the address is empty.
+#[cfg(unix)]
+fn unix_remote_addr(addr: Option<std::os::unix::net::SocketAddr>) -> String {
+    addr.and_then(|a| a.as_pathname().map(|p| p.display().to_string()))
+        .unwrap_or_default()
+}
+
+fn message(data: Vec<u8>, remote_addr: &str) -> Message {
+    let mut message = Message {
+        data,
+        ..Default::default()
+    };
+    message
+        .metadata
+        .insert("socket_remote_addr".into(), remote_addr.into());
+    message
+}
+
+#[typetag::serde(name = "socket_server")]
+impl Source for SocketServer {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        let (tx, rx) = sync_channel(BUFFER_SIZE);
+        match self.network {
+            Network::Tcp => self.listen_tcp(tx)?,
+            Network::Udp => self.listen_udp(tx)?,
+            #[cfg(unix)]
+            Network::Unix => self.listen_unix(tx)?,
+            #[cfg(unix)]
+            Network::Unixgram => self.listen_unixgram(tx)?,
+        }
+
+        for message in rx {
+            let mut batch = MessageBatch::default();
+            batch.messages.push(message);
+            f(Transaction { batch }).wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(unix)]
+    Unixgram(UnixDatagram),
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "SocketConfig")]
+struct Socket {
+    network: Network,
+    address: String,
+    #[serde(default)]
+    framing: Framing,
+    #[serde(flatten)]
+    backoff: Backoff,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+}
+
+#[derive(Deserialize)]
+struct SocketConfig {
+    network: Network,
+    address: String,
+    #[serde(default)]
+    framing: Framing,
+    #[serde(flatten)]
+    backoff: Backoff,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+}
+
+impl TryFrom<SocketConfig> for Socket {
+    type Error = Error;
+
+    fn try_from(config: SocketConfig) -> Result<Socket, Error> {
+        config.network.check_framing(&config.framing)?;
+        Ok(Socket {
+            network: config.network,
+            address: config.address,
+            framing: config.framing,
+            backoff: config.backoff,
+            max_retries: config.max_retries,
+        })
+    }
+}
+
+impl Socket {
+    fn connect(&self) -> Result<Connection, Error> {
+        Ok(match self.network {
+            Network::Tcp => Connection::Tcp(TcpStream::connect(&self.address)?),
+            Network::Udp => {
+                let socket = UdpSocket::bind(if self.address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket.connect(&self.address)?;
+                Connection::Udp(socket)
+            }
+            #[cfg(unix)]
+            Network::Unix => Connection::Unix(UnixStream::connect(&self.address)?),
+            #[cfg(unix)]
+            Network::Unixgram => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.address)?;
+                Connection::Unixgram(socket)
+            }
+        })
+    }
+}
+
+impl Connection {
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let sent = match self {
+            Connection::Tcp(stream) => return Ok(stream.write_all(data)?),
+            Connection::Udp(socket) => socket.send(data)?,
+            #[cfg(unix)]
+            Connection::Unix(stream) => return Ok(stream.write_all(data)?),
+            #[cfg(unix)]
+            Connection::Unixgram(socket) => socket.send(data)?,
+        };
+        if sent != data.len() {
+            return Err(format_err!("sent {} of {} bytes", sent, data.len()));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[typetag::serde(name = "socket")]
+impl Sink for Socket {
+    fn create(&self) -> WriteHandler {
+        let socket = self.clone();
+        let connection: Arc<Mutex<Option<Connection>>> = Arc::default();
+
+        Box::new(move |batches| {
+            let (socket, connection) = (socket.clone(), connection.clone());
+
+            let result = batches.for_each(move |batch| {
+                let mut connection = connection.lock().unwrap();
+                for m in batch.messages {
+                    let framed = if socket.network.is_datagram() {
+                        m.data
+                    } else {
+                        socket.framing.write(&m.data)
+                    };
+                    let sent = socket
+                        .backoff
+                        .retry(socket.max_retries, &socket.address, || {
+                            let mut connected = match connection.take() {
+                                Some(connected) => connected,
+                                None => socket.connect()?,
+                            };
+                            connected.send(&framed)?;
+                            *connection = Some(connected);
+                            Ok(())
+                        });
+                    if let Err(e) = sent {
+                        return result(Err(e));
+                    }
+                }
+
+                let flushed = connection.as_mut().map_or(Ok(()), Connection::flush);
+                result(flushed.map_err(Error::from))
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Cursor, sync::mpsc::channel, time::Duration};
+
+    use futures::future::ok;
+
+    use crate::{no_metdata_batches, no_metdata_messages};
+
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    const LENGTH_PREFIXED: Framing = Framing::LengthPrefixed {
+        max_frame_size: 1024,
+    };
+
+    const OCTET_COUNTING: Framing = Framing::OctetCounting {
+        max_frame_size: 1024,
+    };
+
+    fn frames(framing: Framing, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = Cursor::new(data);
+        let mut frames = Vec::new();
+        while let Some(frame) = framing.read(&mut reader).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn framing_test() {
+        assert_eq!(
+            frames(Framing::default(), b"cheese\r\nbrie\n\ngoat"),
+            vec![
+                b"cheese".to_vec(),
+                b"brie".to_vec(),
+                b"".to_vec(),
+                b"goat".to_vec()
+            ]
+        );
+        assert_eq!(
+            frames(
+                Framing::Delimiter {
+                    delimiter: "||".into(),
+                    max_frame_size: 1024,
+                },
+                b"che|ese||brie||"
+            ),
+            vec![b"che|ese".to_vec(), b"brie".to_vec()]
+        );
+        assert_eq!(
+            frames(LENGTH_PREFIXED, b"\x00\x00\x00\x06cheese\x00\x00\x00\x00"),
+            vec![b"cheese".to_vec(), b"".to_vec()]
+        );
+        assert_eq!(
+            frames(OCTET_COUNTING, b"7 che\nese<1> brie\n<2> goat"),
+            vec![
+                b"che\nese".to_vec(),
+                b"<1> brie".to_vec(),
+                b"<2> goat".to_vec()
+            ]
+        );
+
+        for framing in [
+            Framing::default(),
+            Framing::Delimiter {
+                delimiter: "||".into(),
+                max_frame_size: 1024,
+            },
+            LENGTH_PREFIXED,
+            OCTET_COUNTING,
+        ] {
+            let written = [framing.write(b"cheese"), framing.write(b"brie")].concat();
+            assert_eq!(
+                frames(framing, &written),
+                vec![b"cheese".to_vec(), b"brie".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn framing_errors_test() {
+        let error =
+            |framing: Framing, data: &[u8]| framing.read(&mut Cursor::new(data)).unwrap_err();
+
+        let e = error(LENGTH_PREFIXED, b"\x00\x00\x04\x01cheese");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "frame of 1025 bytes is over 1024");
+
+        // The stream ending mid prefix or mid frame is an error, not a clean end.
+        let e = error(LENGTH_PREFIXED, b"\x00\x00");
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = error(LENGTH_PREFIXED, b"\x00\x00\x00\x06che");
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let e = error(OCTET_COUNTING, b"1025 cheese");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "frame of 1025 bytes is over 1024");
+        // A count too long for any frame is cut off rather than read to the end.
+        let e = error(OCTET_COUNTING, &[b'9'; 4096]);
+        assert_eq!(e.to_string(), "invalid octet count");
+
+        // Delimited frames are cut off at the limit, a missing delimiter doesn't buffer forever.
+        let lines = Framing::Lines { max_frame_size: 6 };
+        assert_eq!(frames(lines.clone(), b"cheese\n"), vec![b"cheese".to_vec()]);
+        let e = error(lines, b"cheeses\n");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "frame is over 6 bytes");
+        let delimiter = Framing::Delimiter {
+            delimiter: "||".into(),
+            max_frame_size: 6,
+        };
+        assert_eq!(
+            frames(delimiter.clone(), b"cheese||"),
+            vec![b"cheese".to_vec()]
+        );
+        let e = error(delimiter, &[b'b'; 4096]);
+        assert_eq!(e.to_string(), "frame is over 6 bytes");
+    }
+
+    #[test]
+    fn socket_datagram_framing_test() {
+        let config = |network: &str, framing: &str| {
+            format!(
+                "type: socket_server\nnetwork: {}\naddress: 127.0.0.1:0\n{}",
+                network, framing
+            )
+        };
+        let length_prefixed = "framing:\n  type: length_prefixed";
+
+        assert!(serde_yaml::from_str::<Box<dyn Source>>(&config("udp", "")).is_ok());
+        assert!(serde_yaml::from_str::<Box<dyn Source>>(&config("tcp", length_prefixed)).is_ok());
+        let e = serde_yaml::from_str::<Box<dyn Source>>(&config("udp", length_prefixed))
+            .err()
+            .unwrap();
+        assert!(e
+            .to_string()
+            .starts_with("framing can't be set for a datagram network"));
+
+        let sink = config("udp", length_prefixed).replace("socket_server", "socket");
+        assert!(serde_yaml::from_str::<Box<dyn Sink>>(&sink).is_err());
+    }
+
+    #[test]
+    fn socket_tcp_test() {
+        let address = free_address();
+        let server = SocketServer {
+            network: Network::Tcp,
+            address: address.clone(),
+            framing: LENGTH_PREFIXED,
+            permissions: None,
+        };
+
+        let receiver = thread::spawn(move || crate::run_source!(server, 2));
+
+        let sink = Socket {
+            network: Network::Tcp,
+            address,
+            framing: LENGTH_PREFIXED,
+            backoff: Backoff {
+                min_backoff_ms: 10,
+                max_backoff_ms: 100,
+            },
+            max_retries: default_max_retries(),
+        };
+        crate::run_sink!(
+            sink,
+            no_metdata_batches![no_metdata_messages![b"cheese", b"brie"]]
+        );
+
+        let messages = receiver
+            .join()
+            .unwrap()
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages.iter().map(|m| &m.data[..]).collect::<Vec<_>>(),
+            vec![&b"cheese"[..], b"brie"]
+        );
+        assert!(messages[0].metadata["socket_remote_addr"].starts_with("127.0.0.1:"));
+    }
+
+    #[test]
+    fn socket_unreachable_test() {
+        let address = free_address();
+        let sink = Socket {
+            network: Network::Tcp,
+            address: address.clone(),
+            framing: Framing::default(),
+            backoff: Backoff {
+                min_backoff_ms: 1,
+                max_backoff_ms: 1,
+            },
+            max_retries: 2,
+        };
+
+        let batches = no_metdata_batches![no_metdata_messages![b"cheese"]];
+        let result = sink.create()(Box::new(futures::stream::iter_ok(batches))).wait();
+
+        let e = result.unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!("failed to write to {} after 2 retries", address)),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn socket_udp_test() {
+        let address = free_address();
+        let server = SocketServer {
+            network: Network::Udp,
+            address: address.clone(),
+            framing: Framing::default(),
+            permissions: None,
+        };
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            server
+                .start(Box::new(move |transaction| {
+                    tx.send(transaction.batch).unwrap();
+                    Box::new(ok(Vec::new()))
+                }))
+                .unwrap()
+        });
+
+        // The server binds asynchronously, keep sending until it picks something up.
+        let sink = Socket {
+            network: Network::Udp,
+            address,
+            framing: Framing::default(),
+            backoff: Backoff {
+                min_backoff_ms: 10,
+                max_backoff_ms: 100,
+            },
+            max_retries: default_max_retries(),
+        };
+        let first = loop {
+            crate::run_sink!(
+                sink.clone(),
+                no_metdata_batches![no_metdata_messages![b"cheese"]]
+            );
+            if let Ok(batch) = rx.recv_timeout(Duration::from_millis(50)) {
+                break batch;
+            }
+        };
+        assert_eq!(first.messages[0].data, b"cheese");
+        assert!(first.messages[0].metadata["socket_remote_addr"].starts_with("127.0.0.1:"));
+
+        // A datagram is one message whatever it holds, the framing is not applied.
+        crate::run_sink!(
+            sink,
+            no_metdata_batches![no_metdata_messages![b"brie\ngoat"]]
+        );
+        let second = rx
+            .iter()
+            .map(|batch| batch.messages[0].data.clone())
+            .find(|data| data != b"cheese")
+            .unwrap();
+        assert_eq!(second, b"brie\ngoat");
+    }
+
+    #[test]
+    fn socket_udp_dropped_test() {
+        let address = free_address();
+        let server = SocketServer {
+            network: Network::Udp,
+            address: address.clone(),
+            framing: Framing::default(),
+            permissions: None,
+        };
+
+        // The pipeline never gets past the first message, so the buffer fills up.
+        let (release, released) = channel::<()>();
+        let released = Mutex::new(released);
+        thread::spawn(move || {
+            server.start(Box::new(move |_| {
+                released.lock().unwrap().recv().ok();
+                Box::new(ok(Vec::new()))
+            }))
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dropped = DROPPED.get();
+        for _ in 0..100_000 {
+            socket.send_to(b"cheese", &address).ok();
+            if DROPPED.get() > dropped {
+                break;
+            }
+        }
+        assert!(DROPPED.get() > dropped);
+        drop(release);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_unix_test() {
+        for network in [Network::Unix, Network::Unixgram] {
+            let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let address = path.to_str().unwrap().to_owned();
+            // A stale socket from a previous run is replaced.
+            UnixListener::bind(&path).unwrap();
+
+            let server = SocketServer {
+                network,
+                address: address.clone(),
+                framing: Framing::default(),
+                permissions: Some("0600".into()),
+            };
+            let receiver = thread::spawn(move || crate::run_source!(server, 2));
+
+            let sink = Socket {
+                network,
+                address,
+                framing: Framing::default(),
+                backoff: Backoff {
+                    min_backoff_ms: 10,
+                    max_backoff_ms: 100,
+                },
+                max_retries: default_max_retries(),
+            };
+            crate::run_sink!(
+                sink,
+                no_metdata_batches![no_metdata_messages![b"cheese", b"brie"]]
+            );
+
+            let messages = receiver
+                .join()
+                .unwrap()
+                .into_iter()
+                .flat_map(|b| b.messages)
+                .map(|m| m.data)
+                .collect::<Vec<_>>();
+            assert_eq!(messages, vec![b"cheese".to_vec(), b"brie".to_vec()]);
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_unix_in_use_test() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let server = SocketServer {
+            network: Network::Unix,
+            address: path.to_str().unwrap().to_owned(),
+            framing: Framing::default(),
+            permissions: Some("0600".into()),
+        };
+        let e = server
+            .start(Box::new(|_| Box::new(ok(Vec::new()))))
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("{} is already in use", server.address)
+        );
+        assert!(fs::metadata(&path).unwrap().file_type().is_socket());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,21 +1,31 @@
 use std::{
-    io::{self, BufRead},
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
     str,
 };
 
 use failure::Error;
 use futures::Future;
 use serde::{Deserialize, Serialize};
-use typetag::serde;
 
-use crate::{BoxFn, Message, MessageBatch, Source, Transaction};
+#[cfg(feature = "generate")]
+use crate::interpolate::Template;
+#[cfg(feature = "http_server")]
+use crate::{
+    auth::{Auth, AuthError, MAX_SIGNED_BODY_SIZE},
+    tls::Listener,
+    tls::Tls,
+};
+use crate::{socket::Framing, Message, MessageBatch, Source, Transaction, TransactionHandler};
 
 #[derive(Default, Deserialize, Serialize)]
 struct StdIn;
 
 #[typetag::serde(name = "stdin")]
 impl Source for StdIn {
-    fn start(&self, f: BoxFn<Transaction, Error>) -> Result<(), Error> {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
         let input = io::stdin();
         for line in input.lock().lines() {
             let mut batch = MessageBatch::default();
@@ -29,50 +39,1300 @@ impl Source for StdIn {
     }
 }
 
+/// Reads frames from a file. A named pipe is reopened whenever its writers close, so it is read
+/// until nekton stops.
+#[derive(Deserialize, Serialize)]
+struct File {
+    path: PathBuf,
+    #[serde(default)]
+    framing: Framing,
+}
+
+#[typetag::serde(name = "file")]
+impl Source for File {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        let path = self.path.display().to_string();
+        loop {
+            // Opening a named pipe blocks until there is a writer.
+            let file = fs::File::open(&self.path)?;
+            let fifo = is_fifo(&file.metadata()?);
+            let mut reader = BufReader::new(file);
+
+            while let Some(data) = self.framing.read(&mut reader)? {
+                let mut message = Message {
+                    data,
+                    ..Default::default()
+                };
+                message.metadata.insert("file_path".into(), path.clone());
+
+                let mut batch = MessageBatch::default();
+                batch.messages.push(message);
+                f(Transaction { batch }).wait()?;
+            }
+
+            if !fifo {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    metadata.file_type().is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod file_tests {
+    use super::*;
+
+    use std::{env, io::Write, sync::mpsc::channel, thread};
+
+    use futures::future::ok;
+
+    #[test]
+    fn file_test() {
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, "cheese\nbrie\n").unwrap();
+
+        let batches = crate::run_source!(File {
+            path: path.clone(),
+            framing: Framing::default(),
+        });
+
+        let messages = batches
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages.iter().map(|m| &m.data[..]).collect::<Vec<_>>(),
+            vec![&b"cheese"[..], b"brie"]
+        );
+        assert_eq!(
+            messages[0].metadata["file_path"],
+            path.display().to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_fifo_test() {
+        let path = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let writer_path = path.clone();
+        thread::spawn(move || {
+            // Each writer closing the pipe must not stop the input.
+            for data in &[&b"cheese\n"[..], b"goat\n\n"] {
+                let mut fifo = fs::OpenOptions::new()
+                    .write(true)
+                    .open(&writer_path)
+                    .unwrap();
+                fifo.write_all(data).unwrap();
+            }
+        });
+
+        let batches = crate::run_source!(
+            File {
+                path,
+                framing: Framing::default(),
+            },
+            3
+        );
+
+        assert_eq!(
+            batches
+                .into_iter()
+                .flat_map(|b| b.messages)
+                .map(|m| m.data)
+                .collect::<Vec<_>>(),
+            vec![b"cheese".to_vec(), b"goat".to_vec(), b"".to_vec()]
+        );
+    }
+}
+
+/// Emits messages rendered from a template, on an interval, on a cron schedule or otherwise as
+/// fast as the pipeline takes them.
+#[cfg(feature = "generate")]
+#[derive(Deserialize, Serialize)]
+struct Generate {
+    template: Template,
+    #[serde(default)]
+    interval_ms: Option<u64>,
+    /// A cron expression starting with seconds, e.g. `0 */5 * * * *`.
+    #[serde(default)]
+    cron: Option<String>,
+    /// Stop after this many messages, or never when 0.
+    #[serde(default)]
+    count: u64,
+}
+
+#[cfg(feature = "generate")]
+#[typetag::serde(name = "generate")]
+impl Source for Generate {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        use std::{
+            str::FromStr,
+            thread,
+            time::{Duration, Instant},
+        };
+
+        use chrono::Utc;
+        use failure::format_err;
+
+        let schedule = match &self.cron {
+            Some(_) if self.interval_ms.is_some() => {
+                return Err(format_err!(
+                    "generate takes an interval or a cron schedule, not both"
+                ))
+            }
+            Some(cron) => Some(
+                cron::Schedule::from_str(cron)
+                    .map_err(|e| format_err!("invalid cron schedule {:?}: {}", cron, e))?,
+            ),
+            None => None,
+        };
+
+        let mut next = Instant::now();
+        let mut generated = 0;
+        while self.count == 0 || generated < self.count {
+            if let Some(schedule) = &schedule {
+                thread::sleep(cron_wait(schedule, Utc::now())?);
+            } else if let Some(interval_ms) = self.interval_ms {
+                let interval = Duration::from_millis(interval_ms);
+                thread::sleep(interval_wait(&mut next, Instant::now(), interval));
+            }
+
+            let mut batch = MessageBatch::default();
+            batch.messages.push(Message {
+                data: self.template.render(&Message::default()).into_bytes(),
+                ..Default::default()
+            });
+            f(Transaction { batch }).wait()?;
+            generated += 1;
+        }
+        Ok(())
+    }
+}
+
+/// How long until the schedule's next time after `now`.
+#[cfg(feature = "generate")]
+fn cron_wait(
+    schedule: &cron::Schedule,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<std::time::Duration, Error> {
+    let upcoming = schedule
+        .after(&now)
+        .next()
+        .ok_or_else(|| failure::format_err!("cron schedule has no upcoming times"))?;
+    Ok((upcoming - now).to_std().unwrap_or_default())
+}
+
+/// How long until the message due at `next`, moving `next` on by `interval`. A slow pipeline
+/// delays the next message rather than causing a burst.
+#[cfg(feature = "generate")]
+fn interval_wait(
+    next: &mut std::time::Instant,
+    now: std::time::Instant,
+    interval: std::time::Duration,
+) -> std::time::Duration {
+    let wait = if *next > now {
+        *next - now
+    } else {
+        *next = now;
+        std::time::Duration::default()
+    };
+    *next += interval;
+    wait
+}
+
+#[cfg(all(test, feature = "generate"))]
+mod generate_tests {
+    use super::*;
+
+    use std::{
+        sync::mpsc::channel,
+        time::{Duration, Instant},
+    };
+
+    use futures::future::ok;
+
+    fn generate(interval_ms: Option<u64>, cron: Option<&str>, count: u64) -> Vec<Vec<u8>> {
+        crate::run_source!(Generate {
+            template: Template::parse("${! count:generate_test }").unwrap(),
+            interval_ms,
+            cron: cron.map(str::to_owned),
+            count,
+        })
+        .into_iter()
+        .flat_map(|b| b.messages)
+        .map(|m| m.data)
+        .collect()
+    }
+
+    #[test]
+    fn generate_count_test() {
+        let generated = generate(None, None, 3)
+            .into_iter()
+            .map(|data| String::from_utf8(data).unwrap().parse().unwrap())
+            .collect::<Vec<u64>>();
+
+        assert_eq!(generated.len(), 3);
+        assert!(generated.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn generate_interval_test() {
+        let started = Instant::now();
+        let at = |ms| started + Duration::from_millis(ms);
+        let interval = Duration::from_millis(50);
+        let mut next = started;
+
+        assert_eq!(
+            interval_wait(&mut next, at(0), interval),
+            Duration::default()
+        );
+        assert_eq!(
+            interval_wait(&mut next, at(10), interval),
+            Duration::from_millis(40)
+        );
+        // A pipeline that falls behind gets the next message straight away, then the interval.
+        assert_eq!(
+            interval_wait(&mut next, at(250), interval),
+            Duration::default()
+        );
+        assert_eq!(
+            interval_wait(&mut next, at(260), interval),
+            Duration::from_millis(40)
+        );
+
+        assert_eq!(generate(Some(1), None, 3).len(), 3);
+    }
+
+    #[test]
+    fn generate_cron_test() {
+        use std::str::FromStr;
+
+        use chrono::{TimeZone, Utc};
+
+        let schedule = cron::Schedule::from_str("0 */5 * * * *").unwrap();
+        let wait = |h, m, s, ms| {
+            let now = Utc.ymd(2020, 1, 1).and_hms_milli(h, m, s, ms);
+            cron_wait(&schedule, now).unwrap()
+        };
+        assert_eq!(wait(12, 3, 30, 0), Duration::from_secs(90));
+        assert_eq!(wait(12, 4, 59, 750), Duration::from_millis(250));
+        // A time on the schedule waits for the next one rather than firing twice.
+        assert_eq!(wait(12, 5, 0, 0), Duration::from_secs(300));
+
+        let once_a_year = cron::Schedule::from_str("0 0 0 1 1 * 2019").unwrap();
+        let now = Utc.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        assert!(cron_wait(&once_a_year, now).is_err());
+    }
+
+    #[test]
+    fn generate_config_error_test() {
+        let source = |interval_ms, cron: &str| Generate {
+            template: Template::parse("cheese").unwrap(),
+            interval_ms,
+            cron: Some(cron.to_owned()),
+            count: 1,
+        };
+
+        assert!(source(Some(1000), "* * * * * *")
+            .start(Box::new(|_| Box::new(ok(Vec::new()))))
+            .is_err());
+        assert!(source(None, "cheese")
+            .start(Box::new(|_| Box::new(ok(Vec::new()))))
+            .is_err());
+    }
+}
+
 #[cfg(feature = "http_server")]
 #[derive(Default, Deserialize, Serialize)]
 struct HttpServer {
     address: String,
+    #[serde(default)]
     path: String,
+    #[serde(default)]
+    paths: Vec<String>,
+    /// Accepted request methods, only `POST` is accepted when empty.
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    max_body_size: Option<usize>,
+    #[serde(default)]
+    split: Option<BodySplit>,
+    #[serde(default)]
+    auth: Option<Auth>,
+    #[serde(default)]
+    tls: Option<Tls>,
+    #[serde(default)]
+    sync_response: Option<SyncResponse>,
+}
+
+#[cfg(feature = "http_server")]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BodySplit {
+    JsonArray,
+    Ndjson,
+    Multipart,
+}
+
+#[cfg(feature = "http_server")]
+impl BodySplit {
+    fn split(&self, body: Vec<u8>, content_type: Option<&str>) -> Result<Vec<Message>, Error> {
+        use std::io::Read;
+
+        use failure::format_err;
+        use multipart::server::Multipart;
+
+        match self {
+            BodySplit::JsonArray => serde_json::from_slice::<Vec<serde_json::Value>>(&body)?
+                .iter()
+                .map(|value| {
+                    Ok(Message {
+                        data: serde_json::to_vec(value)?,
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            BodySplit::Ndjson => Ok(body
+                .split(|b| b == &b'\n')
+                .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                .map(|line| Message {
+                    data: line.to_vec(),
+                    ..Default::default()
+                })
+                .collect()),
+            BodySplit::Multipart => {
+                let boundary = content_type
+                    .and_then(|c| {
+                        c.split(';')
+                            .find_map(|p| p.trim().strip_prefix("boundary="))
+                    })
+                    .map(|b| b.trim_matches('"'))
+                    .ok_or_else(|| format_err!("multipart body has no boundary"))?;
+
+                let mut messages = Vec::new();
+                let mut multipart = Multipart::with_body(&body[..], boundary);
+                while let Some(mut field) = multipart.read_entry()? {
+                    let mut message = Message::default();
+                    field.data.read_to_end(&mut message.data)?;
+                    message
+                        .metadata
+                        .insert("http_form_name".into(), field.headers.name.to_string());
+                    if let Some(filename) = field.headers.filename {
+                        message
+                            .metadata
+                            .insert("http_form_filename".into(), filename);
+                    }
+                    if let Some(content_type) = field.headers.content_type {
+                        message
+                            .metadata
+                            .insert("http_form_content_type".into(), content_type.to_string());
+                    }
+                    messages.push(message);
+                }
+                Ok(messages)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http_server")]
+fn request_headers(request: &tiny_http::Request) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+
+    for header in request.headers() {
+        headers
+            .entry(header.field.as_str().as_str().to_ascii_lowercase())
+            .and_modify(|v: &mut String| {
+                v.push_str(", ");
+                v.push_str(header.value.as_str());
+            })
+            .or_insert_with(|| header.value.as_str().to_owned());
+    }
+
+    headers
+}
+
+/// Headers are prefixed with `http_header_` so a client can't set metadata the pipeline acts on,
+/// such as `http_status_code`. Credentials in `authorization` are left out.
+#[cfg(feature = "http_server")]
+fn request_metadata(
+    request: &tiny_http::Request,
+    remote_addr: std::net::SocketAddr,
+    headers: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut metadata = headers
+        .iter()
+        .filter(|(name, _)| *name != "authorization")
+        .map(|(name, value)| (format!("http_header_{}", name), value.clone()))
+        .collect::<HashMap<_, _>>();
+
+    let mut url = request.url().splitn(2, '?');
+    let path = url.next().unwrap_or_default();
+    if let Some(query) = url.next() {
+        for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+            metadata.insert(format!("http_query_{}", k), v.into_owned());
+        }
+    }
+
+    metadata.insert("http_method".into(), request.method().as_str().to_owned());
+    metadata.insert("http_path".into(), path.to_owned());
+    metadata.insert("http_remote_addr".into(), remote_addr.to_string());
+
+    metadata
+}
+
+#[cfg(feature = "http_server")]
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+struct SyncResponse {
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+#[cfg(feature = "http_server")]
+impl SyncResponse {
+    fn respond(&self, batches: Vec<MessageBatch>) -> tiny_http::Response<io::Cursor<Vec<u8>>> {
+        use tiny_http::{Header, Response};
+
+        let messages = batches
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .collect::<Vec<_>>();
+
+        let first = match messages.first() {
+            Some(message) => message,
+            None => return Response::from_data(Vec::new()).with_status_code(204),
+        };
+
+        let status_code = first
+            .metadata
+            .get("http_status_code")
+            .and_then(|c| c.parse::<u16>().ok())
+            .unwrap_or(200);
+
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|name| {
+                let value = first.metadata.get(name)?;
+                Header::from_bytes(name.as_bytes(), value.as_bytes()).ok()
+            })
+            .collect::<Vec<_>>();
+
+        let body = messages
+            .iter()
+            .map(|m| &m.data[..])
+            .collect::<Vec<_>>()
+            .join(&b'\n');
+
+        let mut response = Response::from_data(body).with_status_code(status_code);
+        for header in headers {
+            response.add_header(header);
+        }
+        response
+    }
 }
 
 #[cfg(feature = "http_server")]
 #[typetag::serde(name = "http_server")]
 impl Source for HttpServer {
-    fn start(&self, f: BoxFn<Transaction, Error>) -> Result<(), Error> {
-        use tiny_http::{Method, Response, Server};
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        use std::io::Read;
 
-        let server = Server::http(&self.address).unwrap();
+        use log::error;
+        use tiny_http::{Header, Response};
 
-        let path = self.path.clone();
+        let listener = Listener::bind(&self.address, self.tls.as_ref())?;
 
-        for mut request in server.incoming_requests() {
-            if request.method() != &Method::Post {
-                let response = Response::empty(405);
-                request.respond(response).unwrap();
+        let paths = self
+            .paths
+            .iter()
+            .chain(Some(&self.path).filter(|p| !p.is_empty()))
+            .collect::<Vec<_>>();
+
+        // A signed body is held in full to verify it, so it is always capped.
+        let max_body_size = self.max_body_size.or_else(|| {
+            self.auth
+                .as_ref()
+                .filter(|a| a.signs_body())
+                .map(|_| MAX_SIGNED_BODY_SIZE)
+        });
+        let too_large = |length: usize| max_body_size.is_some_and(|max| length > max);
+
+        let authenticator = self.auth.as_ref().map(Auth::authenticator).transpose()?;
+        let denied = |headers: &HashMap<String, String>| {
+            authenticator
+                .as_ref()
+                .and_then(|a| a.verify_headers(headers).err())
+        };
+        let rejected = |e: AuthError| {
+            let mut response = Response::from_data(Vec::new()).with_status_code(e.status_code());
+            if let Some(challenge) = self.auth.as_ref().and_then(Auth::challenge) {
+                response.add_header(Header::from_bytes("WWW-Authenticate", challenge).unwrap());
+            }
+            response
+        };
+
+        loop {
+            let (mut request, remote_addr) = listener.recv()?;
+            let headers = request_headers(&request);
+            let metadata = request_metadata(&request, remote_addr, &headers);
+
+            let method = request.method().as_str();
+            let allowed = if self.methods.is_empty() {
+                method == "POST"
+            } else {
+                self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            };
+
+            let response = if !allowed {
+                Response::from_data(Vec::new()).with_status_code(405)
+            } else if !paths.iter().any(|p| p == &&metadata["http_path"]) {
+                Response::from_data(Vec::new()).with_status_code(404)
+            } else if let Some(e) = denied(&headers) {
+                rejected(e)
+            } else if request.body_length().is_some_and(too_large) {
+                Response::from_data(Vec::new()).with_status_code(413)
+            } else {
+                let mut buffer = Vec::new();
+                let read = match max_body_size {
+                    Some(max) => {
+                        Read::take(request.as_reader(), max as u64 + 1).read_to_end(&mut buffer)
+                    }
+                    None => request.as_reader().read_to_end(&mut buffer),
+                };
+
+                match read {
+                    Ok(_) if too_large(buffer.len()) => {
+                        Response::from_data(Vec::new()).with_status_code(413)
+                    }
+                    Ok(_) => {
+                        let verified = authenticator
+                            .as_ref()
+                            .map(|a| a.verify_body(&headers, &buffer));
+                        match verified {
+                            Some(Err(e)) => rejected(e),
+                            _ => self.handle(&f, buffer, metadata),
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to read request body: {}", e);
+                        Response::from_string(e.to_string()).with_status_code(400)
+                    }
+                }
+            };
+
+            if let Err(e) = request.respond(response) {
+                error!("Failed to send response: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "http_server")]
+impl HttpServer {
+    fn handle(
+        &self,
+        f: &TransactionHandler,
+        body: Vec<u8>,
+        metadata: HashMap<String, String>,
+    ) -> tiny_http::Response<io::Cursor<Vec<u8>>> {
+        use log::error;
+        use tiny_http::Response;
+
+        let content_type = metadata.get("http_header_content-type").map(|c| &**c);
+        let messages = match &self.split {
+            Some(split) => match split.split(body, content_type) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Failed to split request body: {}", e);
+                    return Response::from_string(e.to_string()).with_status_code(400);
+                }
+            },
+            None => vec![Message {
+                data: body,
+                ..Default::default()
+            }],
+        };
+
+        let messages = messages
+            .into_iter()
+            .map(|mut message| {
+                for (k, v) in &metadata {
+                    message
+                        .metadata
+                        .entry(k.clone())
+                        .or_insert_with(|| v.clone());
+                }
+                message
+            })
+            .collect();
+
+        let batch = MessageBatch {
+            messages,
+            ..Default::default()
+        };
+
+        match (f(Transaction { batch }).wait(), &self.sync_response) {
+            (Ok(batches), Some(sync_response)) => sync_response.respond(batches),
+            (Ok(_), None) => Response::from_data(Vec::new()).with_status_code(201),
+            (Err(e), _) => {
+                error!("Failed to process request: {}", e);
+                Response::from_string(e.to_string()).with_status_code(500)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http_server"))]
+mod http_server_tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::{Shutdown, TcpListener, TcpStream},
+        sync::mpsc::{channel, Receiver},
+        thread,
+        time::Duration,
+    };
+
+    use failure::format_err;
+    use futures::future::{err, ok};
+
+    fn start(server: HttpServer, f: TransactionHandler) {
+        thread::spawn(move || server.start(f).unwrap());
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fn start_capture(server: HttpServer) -> Receiver<MessageBatch> {
+        let (tx, rx) = channel();
+        start(
+            server,
+            Box::new(move |t| {
+                tx.send(t.batch.clone()).unwrap();
+                Box::new(ok(vec![t.batch]))
+            }),
+        );
+        rx
+    }
+
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn request(address: &str, method: &str, target: &str, headers: &[&str], body: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            target,
+            address,
+            body.len(),
+        )
+        .unwrap();
+        for header in headers {
+            write!(stream, "{}\r\n", header).unwrap();
+        }
+        write!(stream, "\r\n{}", body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn post(address: &str, path: &str, body: &str) -> String {
+        request(address, "POST", path, &[], body)
+    }
+
+    #[test]
+    fn http_server_created_test() {
+        let address = free_address();
+        start(
+            HttpServer {
+                address: address.clone(),
+                path: "/cheese".into(),
+                ..HttpServer::default()
+            },
+            Box::new(|tx| Box::new(ok(vec![tx.batch]))),
+        );
+
+        let response = post(&address, "/cheese", "brie");
+
+        assert!(response.starts_with("HTTP/1.1 201"));
+    }
+
+    #[test]
+    fn http_server_bind_error_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let server = HttpServer {
+            address: listener.local_addr().unwrap().to_string(),
+            path: "/cheese".into(),
+            ..HttpServer::default()
+        };
+
+        assert!(server.start(Box::new(|_| Box::new(ok(vec![])))).is_err());
+    }
+
+    #[test]
+    fn http_server_metadata_test() {
+        let address = free_address();
+        let rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            ..HttpServer::default()
+        });
+
+        request(
+            &address,
+            "POST",
+            "/cheese?kind=blue%20cheese",
+            &["X-Cheese: stilton"],
+            "brie",
+        );
+
+        let metadata = &rx.recv().unwrap().messages[0].metadata;
+        assert_eq!(metadata["http_header_x-cheese"], "stilton");
+        assert_eq!(metadata["http_query_kind"], "blue cheese");
+        assert_eq!(metadata["http_method"], "POST");
+        assert_eq!(metadata["http_path"], "/cheese");
+        assert!(metadata["http_remote_addr"].starts_with("127.0.0.1:"));
+    }
+
+    #[test]
+    fn http_server_paths_and_methods_test() {
+        let address = free_address();
+        let _rx = start_capture(HttpServer {
+            address: address.clone(),
+            paths: vec!["/cheese".into(), "/bacon".into()],
+            methods: vec!["PUT".into(), "POST".into()],
+            ..HttpServer::default()
+        });
+
+        assert!(request(&address, "PUT", "/bacon", &[], "").starts_with("HTTP/1.1 201"));
+        assert!(post(&address, "/cheese", "").starts_with("HTTP/1.1 201"));
+        assert!(post(&address, "/goat", "").starts_with("HTTP/1.1 404"));
+        assert!(request(&address, "DELETE", "/cheese", &[], "").starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn http_server_max_body_size_test() {
+        let address = free_address();
+        let _rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            max_body_size: Some(4),
+            ..HttpServer::default()
+        });
+
+        assert!(post(&address, "/cheese", "brie").starts_with("HTTP/1.1 201"));
+        assert!(post(&address, "/cheese", "stilton").starts_with("HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn http_server_auth_test() {
+        let address = free_address();
+        let rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            auth: Some(Auth::Bearer {
+                tokens: vec!["brie".into()],
+            }),
+            ..HttpServer::default()
+        });
+
+        let unauthorized = post(&address, "/cheese", "");
+        let forbidden = request(
+            &address,
+            "POST",
+            "/cheese",
+            &["Authorization: Bearer feta"],
+            "",
+        );
+        let created = request(
+            &address,
+            "POST",
+            "/cheese",
+            &["Authorization: Bearer brie"],
+            "",
+        );
+
+        assert!(unauthorized.starts_with("HTTP/1.1 401"));
+        assert!(unauthorized.contains("WWW-Authenticate: Bearer\r\n"));
+        assert!(forbidden.starts_with("HTTP/1.1 403"));
+        assert!(created.starts_with("HTTP/1.1 201"));
+        let batch = rx.recv().unwrap();
+        assert!(!batch.messages[0]
+            .metadata
+            .contains_key("http_header_authorization"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Sends a request head announcing a body that never comes.
+    fn head(address: &str, headers: &[&str]) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "POST /cheese HTTP/1.1\r\nHost: {}\r\n", address).unwrap();
+        for header in headers {
+            write!(stream, "{}\r\n", header).unwrap();
+        }
+        write!(stream, "\r\n").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn http_server_auth_before_body_test() {
+        let address = free_address();
+        let _rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            max_body_size: Some(4),
+            auth: Some(Auth::Bearer {
+                tokens: vec!["brie".into()],
+            }),
+            ..HttpServer::default()
+        });
+
+        // The credentials are checked before the body is considered.
+        let response = head(
+            &address,
+            &["Authorization: Bearer feta", "Content-Length: 100000"],
+        );
+        assert!(response.starts_with("HTTP/1.1 403"));
+        let response = head(
+            &address,
+            &["Authorization: Bearer brie", "Content-Length: 100000"],
+        );
+        assert!(response.starts_with("HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn http_server_signed_body_size_test() {
+        let address = free_address();
+        let _rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            auth: Some(Auth::Hmac {
+                secret: "cheese".into(),
+                scheme: Default::default(),
+                header: None,
+                tolerance_secs: 0,
+            }),
+            ..HttpServer::default()
+        });
+
+        // Signed bodies are capped even without a max_body_size.
+        let length = format!("Content-Length: {}", MAX_SIGNED_BODY_SIZE + 1);
+        assert!(head(&address, &[&length]).starts_with("HTTP/1.1 401"));
+        let response = head(&address, &["X-Hub-Signature-256: sha256=00", &length]);
+        assert!(response.starts_with("HTTP/1.1 413"));
+    }
+
+    fn split(split: BodySplit, headers: &[&str], body: &str) -> (String, Vec<Message>) {
+        let address = free_address();
+        let rx = start_capture(HttpServer {
+            address: address.clone(),
+            path: "/cheese".into(),
+            split: Some(split),
+            ..HttpServer::default()
+        });
+
+        let response = request(&address, "POST", "/cheese", headers, body);
+        let messages = rx
+            .recv_timeout(Duration::from_millis(100))
+            .map(|b| b.messages)
+            .unwrap_or_default();
+        (response, messages)
+    }
+
+    #[test]
+    fn http_server_split_json_array_test() {
+        let (_, messages) = split(BodySplit::JsonArray, &[], r#"[{"a": 1}, "b", 3]"#);
+
+        assert_eq!(
+            messages.iter().map(|m| &m.data[..]).collect::<Vec<_>>(),
+            vec![&br#"{"a":1}"#[..], br#""b""#, b"3"]
+        );
+    }
+
+    #[test]
+    fn http_server_split_invalid_json_array_test() {
+        let (response, messages) = split(BodySplit::JsonArray, &[], r#"{"a": 1}"#);
+
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn http_server_split_ndjson_test() {
+        let (_, messages) = split(BodySplit::Ndjson, &[], "{\"a\":1}\n\n{\"b\":2}\n");
+
+        assert_eq!(
+            messages.iter().map(|m| &m.data[..]).collect::<Vec<_>>(),
+            vec![&br#"{"a":1}"#[..], br#"{"b":2}"#]
+        );
+    }
+
+    #[test]
+    fn http_server_split_multipart_test() {
+        let body = "--XYZ\r\n\
+                    Content-Disposition: form-data; name=\"cheese\"\r\n\r\n\
+                    brie\r\n\
+                    --XYZ\r\n\
+                    Content-Disposition: form-data; name=\"file\"; filename=\"goat.txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\n\
+                    goat\r\n\
+                    --XYZ--\r\n";
+
+        let (_, messages) = split(
+            BodySplit::Multipart,
+            &["Content-Type: multipart/form-data; boundary=XYZ"],
+            body,
+        );
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].data, b"brie");
+        assert_eq!(messages[0].metadata["http_form_name"], "cheese");
+        assert_eq!(messages[1].data, b"goat");
+        assert_eq!(messages[1].metadata["http_form_filename"], "goat.txt");
+        assert_eq!(messages[1].metadata["http_form_content_type"], "text/plain");
+    }
+
+    #[test]
+    fn http_server_sync_response_test() {
+        let address = free_address();
+        start(
+            HttpServer {
+                address: address.clone(),
+                path: "/cheese".into(),
+                sync_response: Some(SyncResponse {
+                    headers: vec!["Content-Type".into()],
+                }),
+                ..HttpServer::default()
+            },
+            Box::new(|mut tx| {
+                for message in tx.batch.messages.iter_mut() {
+                    message.data = message.data.to_ascii_uppercase();
+                    message
+                        .metadata
+                        .insert("http_status_code".into(), "202".into());
+                    message
+                        .metadata
+                        .insert("Content-Type".into(), "text/cheese".into());
+                }
+                Box::new(ok(vec![tx.batch]))
+            }),
+        );
+
+        let response = post(&address, "/cheese", "brie");
+
+        assert!(response.starts_with("HTTP/1.1 202"));
+        assert!(response.contains("Content-Type: text/cheese\r\n"));
+        assert!(response.ends_with("\r\n\r\nBRIE"));
+    }
+
+    #[test]
+    fn http_server_sync_response_header_spoofing_test() {
+        let address = free_address();
+        start(
+            HttpServer {
+                address: address.clone(),
+                path: "/cheese".into(),
+                sync_response: Some(SyncResponse {
+                    headers: vec!["location".into()],
+                }),
+                ..HttpServer::default()
+            },
+            Box::new(|tx| Box::new(ok(vec![tx.batch]))),
+        );
+
+        let response = request(
+            &address,
+            "POST",
+            "/cheese",
+            &["http_status_code: 500", "Location: /bacon"],
+            "brie",
+        );
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(!response.to_ascii_lowercase().contains("location"));
+    }
+
+    #[test]
+    fn http_server_sync_response_error_test() {
+        let address = free_address();
+        start(
+            HttpServer {
+                address: address.clone(),
+                path: "/cheese".into(),
+                sync_response: Some(SyncResponse::default()),
+                ..HttpServer::default()
+            },
+            Box::new(|_| Box::new(err(format_err!("mouldy cheese")))),
+        );
+
+        let response = post(&address, "/cheese", "brie");
+
+        assert!(response.starts_with("HTTP/1.1 500"));
+        assert!(response.ends_with("mouldy cheese"));
+    }
+}
+
+#[cfg(feature = "http_client")]
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+struct HttpClient {
+    url: String,
+    #[serde(default = "default_verb")]
+    verb: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[cfg(feature = "http_client")]
+fn default_verb() -> String {
+    "GET".to_owned()
+}
+
+#[cfg(feature = "http_client")]
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+#[cfg(feature = "http_client")]
+#[typetag::serde(name = "http_client")]
+impl Source for HttpClient {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        use std::{thread, time::Duration};
+
+        use futures::Stream;
+        use hyper::{header, Body, Client, Request, StatusCode};
+        use hyper_tls::HttpsConnector;
+        use log::error;
+        use tokio::runtime::Runtime;
+
+        let mut runtime = Runtime::new()?;
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new(4)?);
+
+        let mut etag: Option<String> = None;
+        let mut last_modified: Option<String> = None;
+
+        loop {
+            let mut request = Request::builder();
+            request.method(&*self.verb).uri(&self.url);
+            for (k, v) in &self.headers {
+                request.header(&**k, &**v);
+            }
+            if let Some(etag) = &etag {
+                request.header(header::IF_NONE_MATCH, &**etag);
+            }
+            if let Some(last_modified) = &last_modified {
+                request.header(header::IF_MODIFIED_SINCE, &**last_modified);
+            }
+
+            let response = match runtime.block_on(client.request(request.body(Body::empty())?)) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("Request to {} failed: {}", self.url, e);
+                    thread::sleep(Duration::from_millis(self.interval_ms));
+                    continue;
+                }
+            };
+
+            if response.status() == StatusCode::NOT_MODIFIED {
+                thread::sleep(Duration::from_millis(self.interval_ms));
                 continue;
             }
 
-            if request.url() != &path {
-                let response = Response::empty(404);
-                request.respond(response).unwrap();
+            if !response.status().is_success() {
+                error!("Request to {} returned {}", self.url, response.status());
+                thread::sleep(Duration::from_millis(self.interval_ms));
                 continue;
             }
 
-            let mut batch = MessageBatch::default();
-            let mut buffer = Vec::new();
-            request.as_reader().read_to_end(&mut buffer).unwrap();
-            batch.messages.push(Message {
-                data: buffer,
-                ..Default::default()
-            });
+            let mut metadata: HashMap<String, String> = HashMap::new();
+            for (name, value) in response.headers() {
+                if let Ok(value) = value.to_str() {
+                    metadata
+                        .entry(name.as_str().to_owned())
+                        .and_modify(|v| {
+                            v.push_str(", ");
+                            v.push_str(value);
+                        })
+                        .or_insert_with(|| value.to_owned());
+                }
+            }
+            etag = metadata.get(header::ETAG.as_str()).cloned();
+            last_modified = metadata.get(header::LAST_MODIFIED.as_str()).cloned();
 
-            f(Transaction { batch }).wait().unwrap();
+            let send = |data: Vec<u8>| {
+                let mut batch = MessageBatch::default();
+                batch.messages.push(Message {
+                    data,
+                    metadata: metadata.clone(),
+                });
+                f(Transaction { batch }).wait().map(|_| ())
+            };
+
+            if self.stream {
+                let mut buffer = Vec::new();
+                for chunk in response.into_body().wait() {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            error!("Stream from {} failed: {}", self.url, e);
+                            break;
+                        }
+                    };
+                    buffer.extend_from_slice(&chunk);
+                    while let Some(i) = buffer.iter().position(|b| b == &b'\n') {
+                        let mut line = buffer.drain(..=i).collect::<Vec<_>>();
+                        line.pop();
+                        if line.ends_with(b"\r") {
+                            line.pop();
+                        }
+                        if !line.is_empty() {
+                            send(line)?;
+                        }
+                    }
+                }
+                if !buffer.is_empty() {
+                    send(buffer)?;
+                }
+            } else {
+                match response.into_body().concat2().wait() {
+                    Ok(body) => send(body.to_vec())?,
+                    Err(e) => error!("Reading response from {} failed: {}", self.url, e),
+                }
+            }
 
-            let response = Response::empty(201);
-            request.respond(response).unwrap();
+            thread::sleep(Duration::from_millis(self.interval_ms));
         }
+    }
+}
 
-        Ok(())
+#[cfg(all(test, feature = "http_client", feature = "http_server"))]
+mod http_client_tests {
+    use super::*;
+
+    use std::{
+        sync::mpsc::{channel, Receiver},
+        thread,
+    };
+
+    use futures::future::ok;
+    use tiny_http::{Header, Response, Server};
+
+    /// Answers `responses` requests and then shuts down, sending how many it answered.
+    fn serve(
+        responses: usize,
+        handle: fn(usize, &tiny_http::Request) -> Response<io::Cursor<Vec<u8>>>,
+    ) -> (String, Receiver<usize>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = format!("http://{}/", server.server_addr());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut requests = 0;
+            for request in server.incoming_requests().take(responses) {
+                let response = handle(requests, &request);
+                request.respond(response).unwrap();
+                requests += 1;
+            }
+            tx.send(requests).unwrap();
+        });
+        (address, rx)
+    }
+
+    fn source(url: String, stream: bool) -> HttpClient {
+        HttpClient {
+            url,
+            verb: default_verb(),
+            headers: HashMap::new(),
+            interval_ms: 10,
+            stream,
+        }
+    }
+
+    #[test]
+    fn http_client_poll_test() {
+        let (url, requests) = serve(2, |_, _| {
+            Response::from_string("cheese").with_header("X-Cheese: brie".parse::<Header>().unwrap())
+        });
+
+        let batches = crate::run_source!(source(url, false), 2);
+
+        assert_eq!(requests.recv().unwrap(), 2);
+        assert_eq!(batches[1].messages[0].data, b"cheese");
+        assert_eq!(batches[0].messages[0].data, b"cheese");
+        assert_eq!(
+            batches[0].messages[0].metadata.get("x-cheese"),
+            Some(&"brie".to_owned())
+        );
+    }
+
+    #[test]
+    fn http_client_etag_test() {
+        let (url, requests) = serve(3, |i, request| {
+            let cached = request
+                .headers()
+                .iter()
+                .any(|h| h.field.equiv("If-None-Match") && h.value == "\"v1\"");
+            match i {
+                0 => Response::from_string("cheese")
+                    .with_header("ETag: \"v1\"".parse::<Header>().unwrap()),
+                1 if cached => Response::from_data(Vec::new()).with_status_code(304),
+                _ => Response::from_string("brie")
+                    .with_header("ETag: \"v2\"".parse::<Header>().unwrap()),
+            }
+        });
+
+        let batches = crate::run_source!(source(url, false), 2);
+
+        // The cached request is answered without a message.
+        assert_eq!(requests.recv().unwrap(), 3);
+        assert_eq!(batches[0].messages[0].data, b"cheese");
+        assert_eq!(
+            batches[0].messages[0].metadata.get("etag"),
+            Some(&"\"v1\"".to_owned())
+        );
+        assert_eq!(batches[1].messages[0].data, b"brie");
+    }
+
+    #[test]
+    fn http_client_stream_test() {
+        let (url, requests) = serve(1, |_, _| Response::from_string("cheese\r\ngoat\n\nbrie"));
+
+        let batches = crate::run_source!(source(url, true), 3);
+
+        assert_eq!(requests.recv().unwrap(), 1);
+        assert_eq!(
+            batches
+                .into_iter()
+                .map(|b| b.messages[0].data.clone())
+                .collect::<Vec<_>>(),
+            vec![b"cheese".to_vec(), b"goat".to_vec(), b"brie".to_vec()]
+        );
     }
 }
@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+
+use failure::{format_err, Error};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    socket::{default_max_frame_size, Framing, Network, SocketServer},
+    Message, Source, TransactionHandler,
+};
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Where the parsed header fields end up.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SyslogFormat {
+    /// `syslog_*` metadata, with the message text as the payload.
+    #[default]
+    Metadata,
+    /// A JSON object holding every field and the message text.
+    Json,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Syslog {
+    network: Network,
+    address: String,
+    #[serde(default)]
+    format: SyslogFormat,
+    #[serde(default)]
+    permissions: Option<String>,
+}
+
+#[typetag::serde(name = "syslog")]
+impl Source for Syslog {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        let format = self.format;
+        let server = SocketServer {
+            network: self.network,
+            address: self.address.clone(),
+            framing: Framing::OctetCounting {
+                max_frame_size: default_max_frame_size(),
+            },
+            permissions: self.permissions.clone(),
+        };
+
+        server.start(Box::new(move |mut transaction| {
+            for message in &mut transaction.batch.messages {
+                format.apply(message);
+            }
+            f(transaction)
+        }))
+    }
+}
+
+impl SyslogFormat {
+    /// Replaces a raw syslog line with its parsed form, leaving it untouched with a
+    /// `syslog_error` if it can't be parsed.
+    fn apply(self, message: &mut Message) {
+        let parsed = match parse(&String::from_utf8_lossy(&message.data)) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Failed to parse syslog message: {}", e);
+                message
+                    .metadata
+                    .insert("syslog_error".into(), e.to_string());
+                return;
+            }
+        };
+
+        match self {
+            SyslogFormat::Metadata => {
+                let metadata = &mut message.metadata;
+                metadata.insert("syslog_facility".into(), parsed.facility.into());
+                metadata.insert("syslog_severity".into(), parsed.severity.into());
+                let fields = vec![
+                    ("syslog_timestamp", parsed.timestamp),
+                    ("syslog_hostname", parsed.hostname),
+                    ("syslog_app_name", parsed.app_name),
+                    ("syslog_procid", parsed.procid),
+                    ("syslog_msgid", parsed.msgid),
+                ];
+                for (key, value) in fields {
+                    if let Some(value) = value {
+                        metadata.insert(key.into(), value);
+                    }
+                }
+                for (id, params) in parsed.structured_data {
+                    for (name, value) in params {
+                        metadata.insert(format!("syslog_sd_{}_{}", id, name), value);
+                    }
+                }
+                message.data = parsed.message.into_bytes();
+            }
+            SyslogFormat::Json => {
+                message.data = serde_json::to_vec(&parsed).expect("syslog serialization error");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct Parsed {
+    facility: &'static str,
+    severity: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    procid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    msgid: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    structured_data: BTreeMap<String, BTreeMap<String, String>>,
+    message: String,
+}
+
+fn parse(line: &str) -> Result<Parsed, Error> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let end = line
+        .strip_prefix('<')
+        .and_then(|l| l.find('>'))
+        .ok_or_else(|| format_err!("missing priority"))?;
+    let priority = &line[1..=end];
+    let priority = priority
+        .parse::<usize>()
+        .ok()
+        .filter(|p| *p < FACILITIES.len() * 8)
+        .ok_or_else(|| format_err!("invalid priority {:?}", priority))?;
+
+    let mut parsed = Parsed {
+        facility: FACILITIES[priority / 8],
+        severity: SEVERITIES[priority % 8],
+        ..Default::default()
+    };
+
+    let rest = &line[end + 2..];
+    match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest, &mut parsed)?,
+        None => parse_rfc3164(rest, &mut parsed),
+    }
+    Ok(parsed)
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`
+fn parse_rfc5424(rest: &str, parsed: &mut Parsed) -> Result<(), Error> {
+    let mut fields = rest.splitn(6, ' ');
+    let mut next = || match fields.next() {
+        Some("-") => Ok(None),
+        Some(field) => Ok(Some(field.to_owned())),
+        None => Err(format_err!("truncated RFC 5424 header")),
+    };
+    parsed.timestamp = next()?;
+    parsed.hostname = next()?;
+    parsed.app_name = next()?;
+    parsed.procid = next()?;
+    parsed.msgid = next()?;
+
+    let mut rest = fields.next().unwrap_or_default();
+    if let Some(r) = rest.strip_prefix('-') {
+        rest = r;
+    } else {
+        while rest.starts_with('[') {
+            rest = parse_sd_element(&rest[1..], &mut parsed.structured_data)?;
+        }
+    }
+
+    let message = rest.strip_prefix(' ').unwrap_or(rest);
+    parsed.message = message.trim_start_matches('\u{feff}').to_owned();
+    Ok(())
+}
+
+/// Parses `ID name="value"...]`, returning whatever follows the closing bracket.
+fn parse_sd_element<'a>(
+    element: &'a str,
+    structured_data: &mut BTreeMap<String, BTreeMap<String, String>>,
+) -> Result<&'a str, Error> {
+    let id_end = element
+        .find([' ', ']'])
+        .ok_or_else(|| format_err!("unterminated structured data"))?;
+    let params = structured_data
+        .entry(element[..id_end].to_owned())
+        .or_default();
+
+    let mut rest = &element[id_end..];
+    loop {
+        if let Some(r) = rest.strip_prefix(']') {
+            return Ok(r);
+        }
+        let (name, value) = rest
+            .strip_prefix(' ')
+            .and_then(|r| r.split_once("=\""))
+            .ok_or_else(|| format_err!("invalid structured data parameter"))?;
+
+        let mut unescaped = String::new();
+        let mut chars = value.char_indices();
+        rest = loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, c @ ('"' | '\\' | ']'))) => unescaped.push(c),
+                    Some((_, c)) => {
+                        unescaped.push('\\');
+                        unescaped.push(c);
+                    }
+                    None => break None,
+                },
+                Some((i, '"')) => break Some(&value[i + 1..]),
+                Some((_, c)) => unescaped.push(c),
+                None => break None,
+            }
+        }
+        .ok_or_else(|| format_err!("unterminated structured data value"))?;
+
+        params.insert(name.to_owned(), unescaped);
+    }
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG[PID]: MSG`, where every part of the header is optional.
+fn parse_rfc3164(mut rest: &str, parsed: &mut Parsed) {
+    let is_timestamp = |t: &[u8]| {
+        MONTHS.iter().any(|m| t.starts_with(m.as_bytes()))
+            && t[3] == b' '
+            && t[6] == b' '
+            && t[9] == b':'
+            && t[12] == b':'
+            && t[15] == b' '
+    };
+    if rest.len() > 16 && is_timestamp(&rest.as_bytes()[..16]) {
+        parsed.timestamp = Some(rest[..15].to_owned());
+        rest = &rest[16..];
+
+        // Devices often leave out the hostname and go straight to the tag.
+        if let Some((hostname, r)) = rest.split_once(' ') {
+            if !hostname.is_empty() && !hostname.contains([':', '[']) {
+                parsed.hostname = Some(hostname.to_owned());
+                rest = r;
+            }
+        }
+    }
+
+    let tag_end = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "-_./[]".contains(c)))
+        .filter(|i| *i > 0 && rest[*i..].starts_with(':'));
+    if let Some(tag_end) = tag_end {
+        let tag = &rest[..tag_end];
+        match tag.split_once('[') {
+            Some((app_name, procid)) => {
+                parsed.app_name = Some(app_name.to_owned());
+                parsed.procid = Some(procid.trim_end_matches(']').to_owned());
+            }
+            None => parsed.app_name = Some(tag.to_owned()),
+        }
+        rest = rest[tag_end + 1..].trim_start_matches(' ');
+    }
+
+    parsed.message = rest.to_owned();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::mpsc::channel,
+        thread,
+        time::Duration,
+    };
+
+    use futures::future::ok;
+
+    #[test]
+    fn parse_rfc5424_test() {
+        let parsed = parse(
+            r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="App\"lication"][examplePriority@32473 class="high"] An application event"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::json!({
+                "facility": "local4",
+                "severity": "notice",
+                "timestamp": "2003-10-11T22:14:15.003Z",
+                "hostname": "mymachine.example.com",
+                "app_name": "evntslog",
+                "msgid": "ID47",
+                "structured_data": {
+                    "exampleSDID@32473": {"iut": "3", "eventSource": "App\"lication"},
+                    "examplePriority@32473": {"class": "high"}
+                },
+                "message": "An application event"
+            })
+        );
+
+        let parsed = parse("<34>1 - - su 1234 - - \u{feff}'su root' failed").unwrap();
+        assert_eq!(parsed.procid.as_deref(), Some("1234"));
+        assert_eq!(parsed.message, "'su root' failed");
+
+        assert!(parse("<34>1 2003-10-11T22:14:15.003Z host").is_err());
+        assert!(parse("<34>1 - - - - - [id x=\"1]").is_err());
+        assert!(parse("<999>cheese").is_err());
+        assert!(parse("cheese").is_err());
+    }
+
+    #[test]
+    fn parse_rfc3164_test() {
+        let parsed = parse("<34>Oct 11 22:14:15 mymachine su[230]: 'su root' failed").unwrap();
+        assert_eq!(
+            parsed,
+            Parsed {
+                facility: "auth",
+                severity: "crit",
+                timestamp: Some("Oct 11 22:14:15".into()),
+                hostname: Some("mymachine".into()),
+                app_name: Some("su".into()),
+                procid: Some("230".into()),
+                message: "'su root' failed".into(),
+                ..Default::default()
+            }
+        );
+
+        let parsed = parse("<13>Feb  5 17:32:18 sshd: Accepted publickey").unwrap();
+        assert_eq!(parsed.timestamp.as_deref(), Some("Feb  5 17:32:18"));
+        assert_eq!(parsed.hostname, None);
+        assert_eq!(parsed.app_name.as_deref(), Some("sshd"));
+        assert_eq!(parsed.message, "Accepted publickey");
+
+        let parsed = parse("<13>no header here").unwrap();
+        assert_eq!(parsed.app_name, None);
+        assert_eq!(parsed.message, "no header here");
+    }
+
+    #[test]
+    fn syslog_tcp_test() {
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let syslog = Syslog {
+            network: Network::Tcp,
+            address: address.clone(),
+            format: SyslogFormat::Metadata,
+            permissions: None,
+        };
+        let receiver = thread::spawn(move || crate::run_source!(syslog, 3));
+
+        let mut stream = loop {
+            match TcpStream::connect(&address) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let multiline = "<165>1 - host app - - [meta sequenceId=\"1\"] two\nlines";
+        write!(
+            stream,
+            "{} {}<13>Oct 11 22:14:15 host cron: bacon\ncheese\n",
+            multiline.len(),
+            multiline
+        )
+        .unwrap();
+
+        let messages = receiver
+            .join()
+            .unwrap()
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .collect::<Vec<_>>();
+
+        assert_eq!(messages[0].data, b"two\nlines");
+        assert_eq!(messages[0].metadata["syslog_hostname"], "host");
+        assert_eq!(messages[0].metadata["syslog_app_name"], "app");
+        assert_eq!(messages[0].metadata["syslog_sd_meta_sequenceId"], "1");
+        assert!(!messages[0].metadata.contains_key("syslog_procid"));
+        assert_eq!(messages[1].data, b"bacon");
+        assert_eq!(messages[1].metadata["syslog_facility"], "user");
+        assert_eq!(messages[1].metadata["syslog_severity"], "notice");
+        assert_eq!(messages[2].data, b"cheese");
+        assert_eq!(messages[2].metadata["syslog_error"], "missing priority");
+    }
+
+    #[test]
+    fn syslog_json_test() {
+        let mut message = Message {
+            data: b"<13>Oct 11 22:14:15 host cron: bacon".to_vec(),
+            ..Default::default()
+        };
+        SyslogFormat::Json.apply(&mut message);
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&message.data).unwrap(),
+            serde_json::json!({
+                "facility": "user",
+                "severity": "notice",
+                "timestamp": "Oct 11 22:14:15",
+                "hostname": "host",
+                "app_name": "cron",
+                "message": "bacon"
+            })
+        );
+    }
+}
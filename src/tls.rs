@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use failure::{format_err, Error};
+use log::{debug, error, info};
+use openssl::{
+    ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode},
+    x509::X509Name,
+};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Request, Response, Server};
+
+/// How long a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a relay waits on one side of a connection before polling the other.
+const RELAY_POLL: Duration = Duration::from_millis(10);
+
+/// A PEM certificate and private key to serve with. With a `client_ca`, clients must present a
+/// certificate signed by one of the PEM certificates in it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Tls {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    #[serde(default)]
+    client_ca: Option<PathBuf>,
+}
+
+impl Tls {
+    /// Loads the files, checking that the key belongs to the certificate.
+    fn acceptor(&self) -> Result<SslAcceptor, Error> {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        acceptor.set_certificate_chain_file(&self.cert_file)?;
+        acceptor.set_private_key_file(&self.key_file, SslFiletype::PEM)?;
+        acceptor.check_private_key()?;
+        if let Some(client_ca) = &self.client_ca {
+            acceptor.set_ca_file(client_ca)?;
+            acceptor.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        Ok(acceptor.build())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert_file)
+            .max(modified(&self.key_file))
+            .max(self.client_ca.as_ref().and_then(modified))
+    }
+}
+
+/// Maps the local addresses of relayed connections to the clients they carry.
+type Peers = Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>;
+
+/// A tiny_http server, fronted for TLS by a socket that completes the handshake with the
+/// current [`Acceptor`] and relays the plaintext to the server. A changed certificate is served
+/// from the next connection without rebinding.
+pub struct Listener {
+    server: Server,
+    peers: Option<Peers>,
+}
+
+impl Listener {
+    pub fn bind(address: &str, tls: Option<&Tls>) -> Result<Listener, Error> {
+        let tls = match tls {
+            Some(tls) => tls,
+            None => {
+                let server = Server::http(address)
+                    .map_err(|e| format_err!("failed to bind to {}: {}", address, e))?;
+                return Ok(Listener {
+                    server,
+                    peers: None,
+                });
+            }
+        };
+
+        let mut acceptor = Acceptor::new(tls)?;
+        let front = TcpListener::bind(address)
+            .map_err(|e| format_err!("failed to bind to {}: {}", address, e))?;
+        let server = Server::http("127.0.0.1:0")
+            .map_err(|e| format_err!("failed to bind the server behind {}: {}", address, e))?;
+        let upstream = server.server_addr();
+        let peers = Peers::default();
+
+        let relayed = peers.clone();
+        thread::spawn(move || {
+            for client in front.incoming() {
+                match client {
+                    Ok(client) => {
+                        let acceptor = acceptor.current();
+                        let peers = relayed.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = relay(&acceptor, client, upstream, &peers) {
+                                debug!("TLS connection closed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed to accept a TLS connection: {}", e),
+                }
+            }
+        });
+
+        Ok(Listener {
+            server,
+            peers: Some(peers),
+        })
+    }
+
+    /// Returns the next request with the address of the client that sent it. Requests made to
+    /// the server behind a TLS front without going through it are refused.
+    pub fn recv(&self) -> Result<(Request, SocketAddr), Error> {
+        loop {
+            let request = self.server.recv()?;
+            let peers = match &self.peers {
+                Some(peers) => peers,
+                None => {
+                    let remote_addr = *request.remote_addr();
+                    return Ok((request, remote_addr));
+                }
+            };
+
+            let remote_addr = peers.lock().unwrap().get(request.remote_addr()).cloned();
+            match remote_addr {
+                Some(remote_addr) => return Ok((request, remote_addr)),
+                None => {
+                    if let Err(e) = request.respond(Response::empty(403)) {
+                        error!("Failed to refuse a request bypassing TLS: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Completes the handshake and copies bytes both ways between the client and the server until
+/// the server closes the connection.
+fn relay(
+    acceptor: &SslAcceptor,
+    client: TcpStream,
+    upstream: SocketAddr,
+    peers: &Peers,
+) -> Result<(), Error> {
+    let remote_addr = client.peer_addr()?;
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    client.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut client = acceptor
+        .accept(client)
+        .map_err(|e| format_err!("handshake with {} failed: {}", remote_addr, e))?;
+    client.get_ref().set_read_timeout(Some(RELAY_POLL))?;
+
+    let mut server = TcpStream::connect(upstream)?;
+    server.set_read_timeout(Some(RELAY_POLL))?;
+    let local_addr = server.local_addr()?;
+    peers.lock().unwrap().insert(local_addr, remote_addr);
+
+    let relayed = copy_both_ways(&mut client, &mut server);
+    peers.lock().unwrap().remove(&local_addr);
+    relayed.map_err(Error::from)
+}
+
+fn copy_both_ways(client: &mut SslStream<TcpStream>, server: &mut TcpStream) -> io::Result<()> {
+    let idle = |e: &io::Error| {
+        e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+    };
+
+    let mut buffer = [0; 16 * 1024];
+    let mut client_open = true;
+    loop {
+        if client_open {
+            match client.read(&mut buffer) {
+                Ok(0) => {
+                    client_open = false;
+                    server.shutdown(Shutdown::Write)?;
+                }
+                Ok(n) => server.write_all(&buffer[..n])?,
+                Err(ref e) if idle(e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match server.read(&mut buffer) {
+            Ok(0) => {
+                // The client may already be gone, which doesn't matter once the server is done.
+                client.shutdown().ok();
+                return Ok(());
+            }
+            Ok(n) => client.write_all(&buffer[..n])?,
+            Err(ref e) if idle(e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The acceptor for the current certificate, reloaded when its files change on disk. A set of
+/// files that fails to load leaves the current acceptor in place.
+pub struct Acceptor {
+    tls: Tls,
+    acceptor: SslAcceptor,
+    modified: Option<SystemTime>,
+}
+
+impl Acceptor {
+    pub fn new(tls: &Tls) -> Result<Acceptor, Error> {
+        Ok(Acceptor {
+            tls: tls.clone(),
+            acceptor: tls.acceptor()?,
+            modified: tls.modified(),
+        })
+    }
+
+    pub fn current(&mut self) -> SslAcceptor {
+        let modified = self.tls.modified();
+        if modified != self.modified {
+            self.modified = modified;
+            match self.tls.acceptor() {
+                Ok(acceptor) => {
+                    info!("Reloading certificate {:?}", self.tls.cert_file);
+                    self.acceptor = acceptor;
+                }
+                Err(e) => error!(
+                    "Failed to reload certificate {:?}, keeping the current one: {}",
+                    self.tls.cert_file, e
+                ),
+            }
+        }
+        self.acceptor.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, path::Path};
+
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        ssl::{SslConnector, SslMethod, SslVerifyMode},
+        x509::{X509Name, X509},
+    };
+    use tiny_http::Response;
+
+    fn write_certificate(name: &str, cert_file: &Path, key_file: &Path) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        fs::write(key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        fs::write(cert_file, cert.build().to_pem().unwrap()).unwrap();
+    }
+
+    fn certificate(dir: &Path, name: &str) -> Tls {
+        let tls = Tls {
+            cert_file: dir.join(format!("{}.pem", name)),
+            key_file: dir.join(format!("{}.key", name)),
+            client_ca: None,
+        };
+        write_certificate(name, &tls.cert_file, &tls.key_file);
+        tls
+    }
+
+    /// Serves each request with "cheese" and the client address the listener reports.
+    fn serve(tls: &Tls) -> (String, SocketAddr) {
+        let address = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let listener = Listener::bind(&address, Some(tls)).unwrap();
+        let upstream = listener.server.server_addr();
+        thread::spawn(move || loop {
+            let (request, remote_addr) = listener.recv().unwrap();
+            let response = Response::from_string(format!("cheese {}", remote_addr));
+            request.respond(response).unwrap();
+        });
+        (address, upstream)
+    }
+
+    /// Makes a request with an optional client certificate, returning the server's certificate
+    /// name.
+    fn get(address: &str, identity: Option<&Tls>) -> Result<String, Error> {
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        if let Some(identity) = identity {
+            connector.set_certificate_file(&identity.cert_file, SslFiletype::PEM)?;
+            connector.set_private_key_file(&identity.key_file, SslFiletype::PEM)?;
+        }
+        let stream = TcpStream::connect(address)?;
+        let local_addr = stream.local_addr()?;
+        let mut stream = connector
+            .build()
+            .connect("localhost", stream)
+            .map_err(|e| format_err!("{}", e))?;
+
+        let name = stream
+            .ssl()
+            .peer_certificate()
+            .unwrap()
+            .subject_name()
+            .entries()
+            .next()
+            .unwrap()
+            .data()
+            .to_string()?;
+
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            address
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        if !response.ends_with(&format!("cheese {}", local_addr)) {
+            return Err(format_err!("unexpected response: {}", response));
+        }
+
+        Ok(name)
+    }
+
+    #[test]
+    fn tls_certificate_reload_test() {
+        let dir = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir(&dir).unwrap();
+        let tls = certificate(&dir, "cheese");
+        let (address, _) = serve(&tls);
+
+        assert_eq!(get(&address, None).unwrap(), "cheese");
+
+        write_certificate("bacon", &tls.cert_file, &tls.key_file);
+        assert_eq!(get(&address, None).unwrap(), "bacon");
+
+        // A certificate without its key is ignored.
+        let other = certificate(&dir, "brie");
+        fs::copy(&other.cert_file, &tls.cert_file).unwrap();
+        assert_eq!(get(&address, None).unwrap(), "bacon");
+    }
+
+    #[test]
+    fn tls_client_ca_test() {
+        let dir = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir(&dir).unwrap();
+        let trusted = certificate(&dir, "trusted");
+        let untrusted = certificate(&dir, "untrusted");
+        let tls = Tls {
+            client_ca: Some(trusted.cert_file.clone()),
+            ..certificate(&dir, "cheese")
+        };
+        let (address, _) = serve(&tls);
+
+        assert_eq!(get(&address, Some(&trusted)).unwrap(), "cheese");
+        assert!(get(&address, None).is_err());
+        assert!(get(&address, Some(&untrusted)).is_err());
+    }
+
+    #[test]
+    fn tls_bypass_refused_test() {
+        let dir = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir(&dir).unwrap();
+        let (_, upstream) = serve(&certificate(&dir, "cheese"));
+
+        let mut stream = TcpStream::connect(upstream).unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            upstream
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403"));
+    }
+
+    #[test]
+    fn tls_config_test() {
+        let config = "cert_file: cert.pem\nkey_file: key.pem\nclient_ca: ca.pem";
+        let tls = serde_yaml::from_str::<Tls>(config).unwrap();
+        assert_eq!(tls.client_ca, Some(PathBuf::from("ca.pem")));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use failure::Error;
+use futures::{future::result, Future, Stream};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tungstenite::{client::AutoStream, http, WebSocket};
+
+use crate::{
+    backoff::{default_max_retries, Backoff},
+    Message, MessageBatch, Sink, Source, Transaction, TransactionHandler, WriteHandler,
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Connection {
+    url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(flatten)]
+    backoff: Backoff,
+}
+
+impl Connection {
+    fn connect(&self) -> Result<WebSocket<AutoStream>, Error> {
+        let mut request = http::Request::builder().uri(&self.url);
+        for (k, v) in &self.headers {
+            request = request.header(&**k, &**v);
+        }
+        let (socket, _) = tungstenite::connect(request.body(())?)?;
+        Ok(socket)
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct WebSocketIn {
+    #[serde(flatten)]
+    connection: Connection,
+}
+
+#[typetag::serde(name = "websocket")]
+impl Source for WebSocketIn {
+    fn start(&self, f: TransactionHandler) -> Result<(), Error> {
+        // Only a message shows a connection is healthy, one that is accepted and then dropped
+        // keeps backing off.
+        let mut delay = self.connection.backoff.delay();
+        loop {
+            let mut socket = match self.connection.connect() {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!(
+                        "Failed to connect to {}, retrying in {}ms: {}",
+                        self.connection.url,
+                        delay.next_ms(),
+                        e
+                    );
+                    delay.wait();
+                    continue;
+                }
+            };
+            loop {
+                let (data, frame_type) = match socket.read_message() {
+                    Ok(tungstenite::Message::Text(text)) => (text.into_bytes(), "text"),
+                    Ok(tungstenite::Message::Binary(data)) => (data, "binary"),
+                    Ok(tungstenite::Message::Close(_)) => {
+                        debug!("Connection to {} closed", self.connection.url);
+                        break;
+                    }
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Connection to {} failed: {}", self.connection.url, e);
+                        break;
+                    }
+                };
+                delay.reset();
+
+                let mut message = Message {
+                    data,
+                    ..Default::default()
+                };
+                message
+                    .metadata
+                    .insert("websocket_frame_type".into(), frame_type.into());
+
+                let mut batch = MessageBatch::default();
+                batch.messages.push(message);
+                f(Transaction { batch }).wait()?;
+            }
+            delay.wait();
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct WebSocketOut {
+    #[serde(flatten)]
+    connection: Connection,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+}
+
+#[typetag::serde(name = "websocket")]
+impl Sink for WebSocketOut {
+    fn create(&self) -> WriteHandler {
+        let (connection, max_retries) = (self.connection.clone(), self.max_retries);
+        let socket: Arc<Mutex<Option<WebSocket<AutoStream>>>> = Arc::default();
+
+        Box::new(move |batches| {
+            let (connection, socket) = (connection.clone(), socket.clone());
+
+            let result = batches.for_each(move |batch| {
+                let mut socket = socket.lock().unwrap();
+                for m in batch.messages {
+                    let binary =
+                        m.metadata.get("websocket_frame_type").map(|t| &**t) == Some("binary");
+                    let message = match (binary, String::from_utf8(m.data)) {
+                        (false, Ok(text)) => tungstenite::Message::Text(text),
+                        (_, Ok(text)) => tungstenite::Message::Binary(text.into_bytes()),
+                        (_, Err(e)) => tungstenite::Message::Binary(e.into_bytes()),
+                    };
+
+                    let sent = connection.backoff.retry(max_retries, &connection.url, || {
+                        let mut connected = match socket.take() {
+                            Some(connected) => connected,
+                            None => connection.connect()?,
+                        };
+                        connected.write_message(message.clone())?;
+                        *socket = Some(connected);
+                        Ok(())
+                    });
+                    if let Err(e) = sent {
+                        return result(Err(e));
+                    }
+                }
+
+                result(Ok(()))
+            });
+
+            Box::new(result)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{channel, Sender},
+        thread,
+    };
+
+    use futures::future::ok;
+    use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+
+    use crate::{no_metdata_batches, no_metdata_messages};
+
+    fn connection(url: String) -> Connection {
+        Connection {
+            url,
+            headers: vec![("X-Cheese".to_owned(), "brie".to_owned())]
+                .into_iter()
+                .collect(),
+            backoff: Backoff {
+                min_backoff_ms: 10,
+                max_backoff_ms: 100,
+            },
+        }
+    }
+
+    /// Reports the `X-Cheese` header of each handshake.
+    struct Cheese(Sender<String>);
+
+    impl Callback for Cheese {
+        fn on_request(
+            self,
+            request: &Request,
+            response: Response,
+        ) -> Result<Response, ErrorResponse> {
+            let cheese = request.headers().get("x-cheese").unwrap();
+            self.0.send(cheese.to_str().unwrap().to_owned()).unwrap();
+            Ok(response)
+        }
+    }
+
+    /// Accepts `connections` clients in turn, handing each to `handle`.
+    fn serve(
+        connections: usize,
+        handle: fn(WebSocket<TcpStream>, &Sender<String>),
+    ) -> (String, std::sync::mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let socket = tungstenite::accept_hdr(stream.unwrap(), Cheese(tx.clone())).unwrap();
+                handle(socket, &tx);
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn source_websocket_frame_types_test() {
+        let (url, _rx) = serve(1, |mut socket, _| {
+            socket
+                .write_message(tungstenite::Message::Text("cheese".into()))
+                .unwrap();
+            socket
+                .write_message(tungstenite::Message::Binary(vec![0xff]))
+                .unwrap();
+        });
+
+        let batches = crate::run_source!(
+            WebSocketIn {
+                connection: connection(url),
+            },
+            2
+        );
+
+        let messages = batches
+            .into_iter()
+            .flat_map(|b| b.messages)
+            .map(|m| (m.data, m.metadata["websocket_frame_type"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec![
+                (b"cheese".to_vec(), "text".to_owned()),
+                (vec![0xff], "binary".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn source_websocket_reconnect_test() {
+        let (url, rx) = serve(2, |mut socket, _| {
+            socket.close(None).unwrap();
+            while socket.read_message().is_ok() {}
+        });
+
+        thread::spawn(move || {
+            crate::run_source!(WebSocketIn {
+                connection: connection(url),
+            })
+        });
+
+        assert_eq!(rx.recv().unwrap(), "brie");
+        assert_eq!(rx.recv().unwrap(), "brie");
+    }
+
+    #[test]
+    fn sink_websocket_echo_test() {
+        let (url, rx) = serve(1, |mut socket, tx| {
+            while let Ok(message) = socket.read_message() {
+                match message {
+                    tungstenite::Message::Text(text) => tx.send(format!("text:{}", text)).unwrap(),
+                    tungstenite::Message::Binary(data) => {
+                        tx.send(format!("binary:{:?}", data)).unwrap()
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let mut binary = Message {
+            data: b"goat".to_vec(),
+            ..Default::default()
+        };
+        binary
+            .metadata
+            .insert("websocket_frame_type".into(), "binary".into());
+
+        crate::run_sink!(
+            WebSocketOut {
+                connection: connection(url),
+                max_retries: default_max_retries(),
+            },
+            no_metdata_batches![no_metdata_messages![b"cheese"], vec![binary]]
+        );
+
+        assert_eq!(rx.recv().unwrap(), "brie");
+        assert_eq!(rx.recv().unwrap(), "text:cheese");
+        assert_eq!(rx.recv().unwrap(), "binary:[103, 111, 97, 116]");
+    }
+
+    #[test]
+    fn sink_websocket_unreachable_test() {
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("ws://{}/", listener.local_addr().unwrap())
+        };
+        let sink = WebSocketOut {
+            connection: Connection {
+                backoff: Backoff {
+                    min_backoff_ms: 1,
+                    max_backoff_ms: 1,
+                },
+                ..connection(url.clone())
+            },
+            max_retries: 2,
+        };
+
+        let batches = no_metdata_batches![no_metdata_messages![b"cheese"]];
+        let result = sink.create()(Box::new(futures::stream::iter_ok(batches))).wait();
+
+        let e = result.unwrap_err().to_string();
+        assert!(
+            e.starts_with(&format!("failed to write to {} after 2 retries", url)),
+            "{}",
+            e
+        );
+    }
+}